@@ -1,4 +1,6 @@
+use std::collections::HashSet;
 use std::fs;
+use http::Method;
 use serde::Deserialize;
 use toml;
 
@@ -7,14 +9,52 @@ pub struct Config {
     pub upstream: UpstreamConfig,
     pub log: LogConfig,
     pub server: ServerConfig,
-    pub handler: HandlerConfig
+    pub handler: HandlerConfig,
+    #[serde(default)]
+    pub storage: StorageConfig,
+    #[serde(default)]
+    pub cors: CorsConfig,
 }
 
 impl Config {
     /// panic if unable to validate.
     /// assuming after this validation, all configs are valid
     pub fn validate(&self) {
-        // todo
+        let mut names = HashSet::new();
+        for group in &self.upstream.groups {
+            if !names.insert(group.name.as_str()) {
+                panic!("Duplicate upstream group: {}", group.name);
+            }
+            if group.addresses.is_empty() {
+                panic!("Upstream group {} has no addresses", group.name);
+            }
+        }
+
+        if let Some(passthrough) = &self.upstream.passthrough {
+            if !names.contains(passthrough.as_str()) {
+                panic!("Unknown passthrough upstream group: {}", passthrough);
+            }
+        }
+
+        for route in &self.upstream.routes {
+            if !names.contains(route.group.as_str()) {
+                panic!("Unknown upstream group {} of route {} {}", route.group, route.method, route.path);
+            }
+            if Method::from_bytes(route.method.as_bytes()).is_err() {
+                panic!("Invalid method of upstream route {} {}", route.method, route.path);
+            }
+        }
+
+        let ntor_session = &self.handler.ntor_session;
+        if ntor_session.max_sessions == 0 || ntor_session.reap_interval_secs == 0 {
+            panic!("nTor session max_sessions and reap_interval_secs must be positive");
+        }
+
+        if self.server.max_body_bytes == 0 {
+            panic!("Server max_body_bytes must be positive");
+        }
+
+        self.cors.validate();
     }
 }
 
@@ -27,8 +67,97 @@ impl Config {
 
 #[derive(Debug, Deserialize)]
 pub(super) struct UpstreamConfig {
+    /// name of the upstream group receiving requests that match no route, instead of answering 404
+    pub passthrough: Option<String>,
+    /// decrypt/encrypt passthrough traffic with the client's nTor session
+    #[serde(default)]
+    pub encrypted: bool,
+    #[serde(default)]
+    pub groups: Vec<UpstreamGroupConfig>,
+    /// routes forwarded to an upstream group instead of being handled locally
+    #[serde(default)]
+    pub routes: Vec<UpstreamRouteConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct UpstreamRouteConfig {
+    pub method: String,
+    /// route pattern such as "/archive/{*rest}"
+    pub path: String,
+    /// name of the upstream group the route is forwarded to
+    pub group: String,
+    /// decrypt/encrypt the route's traffic with the client's nTor session
+    #[serde(default)]
+    pub encrypted: bool,
+}
+
+impl UpstreamRouteConfig {
+    pub fn method(&self) -> Method {
+        Method::from_bytes(self.method.as_bytes()).unwrap()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct UpstreamGroupConfig {
+    pub name: String,
+    /// "host:port" of every peer in the group
+    pub addresses: Vec<String>,
+    #[serde(default)]
+    pub selection: UpstreamSelection,
+    pub health_check: Option<HealthCheckConfig>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(super) enum UpstreamSelection {
+    #[default]
+    RoundRobin,
+    /// peers are picked by hashing the nTor session id, or the client ip, so sessions stick to a peer
+    ConsistentHash,
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct HealthCheckConfig {
+    #[serde(default)]
+    pub kind: HealthCheckKind,
+    /// Host header and path requested by http health checks
+    #[serde(default = "HealthCheckConfig::default_host")]
     pub host: String,
-    pub port: u16,
+    #[serde(default = "HealthCheckConfig::default_path")]
+    pub path: String,
+    #[serde(default = "HealthCheckConfig::default_interval_secs")]
+    pub interval_secs: u64,
+    /// number of consecutive failures before a peer is ejected, and successes before it is restored
+    #[serde(default = "HealthCheckConfig::default_threshold")]
+    pub consecutive_failure: usize,
+    #[serde(default = "HealthCheckConfig::default_threshold")]
+    pub consecutive_success: usize,
+}
+
+impl HealthCheckConfig {
+    fn default_host() -> String {
+        "localhost".to_string()
+    }
+
+    fn default_path() -> String {
+        "/".to_string()
+    }
+
+    fn default_interval_secs() -> u64 {
+        5
+    }
+
+    fn default_threshold() -> usize {
+        1
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(super) enum HealthCheckKind {
+    #[default]
+    Tcp,
+    Http,
 }
 
 #[derive(Debug, Deserialize)]
@@ -51,9 +180,71 @@ impl LogConfig {
     }
 }
 
+/// Cross-origin resource sharing policy applied to every response, and to preflight requests of registered routes.
+#[derive(Debug, Deserialize, Clone)]
+pub(super) struct CorsConfig {
+    /// exact origins such as "https://app.example.com", patterns such as "https://*.example.com", or "*" for any
+    #[serde(default = "CorsConfig::default_allowed_origins")]
+    pub allowed_origins: Vec<String>,
+    /// methods allowed by preflight requests among the route's own methods, all of them when empty
+    #[serde(default)]
+    pub allowed_methods: Vec<String>,
+    /// request headers allowed by preflight requests, or "*" for any
+    #[serde(default = "CorsConfig::default_allowed_headers")]
+    pub allowed_headers: Vec<String>,
+    #[serde(default)]
+    pub allow_credentials: bool,
+    /// how long browsers may cache a preflight response
+    #[serde(default = "CorsConfig::default_max_age_secs")]
+    pub max_age_secs: u64,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        CorsConfig {
+            allowed_origins: Self::default_allowed_origins(),
+            allowed_methods: Vec::new(),
+            allowed_headers: Self::default_allowed_headers(),
+            allow_credentials: false,
+            max_age_secs: Self::default_max_age_secs(),
+        }
+    }
+}
+
+impl CorsConfig {
+    /// panic if unable to validate.
+    pub fn validate(&self) {
+        // browsers refuse credentialed responses allowing any origin
+        if self.allow_credentials && self.allowed_origins.iter().any(|origin| origin == "*") {
+            panic!("CORS allow_credentials cannot be used with the \"*\" origin");
+        }
+    }
+
+    fn default_allowed_origins() -> Vec<String> {
+        vec!["*".to_string()]
+    }
+
+    fn default_allowed_headers() -> Vec<String> {
+        ["Content-Type", "Authorization", "nTor_session_id"].map(String::from).to_vec()
+    }
+
+    fn default_max_age_secs() -> u64 {
+        24 * 60 * 60
+    }
+}
+
 #[derive(Debug, Deserialize)]
 pub(super) struct ServerConfig {
-    pub address: String
+    pub address: String,
+    /// larger request bodies are answered with 413, whether they are handled locally or forwarded to the upstream
+    #[serde(default = "ServerConfig::default_max_body_bytes")]
+    pub max_body_bytes: usize,
+}
+
+impl ServerConfig {
+    fn default_max_body_bytes() -> usize {
+        10 * 1024 * 1024
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -61,6 +252,124 @@ pub(super) struct HandlerConfig {
     pub jwt_secret: String,
     pub ntor_server_id: String,
     pub ntor_static_secret: String,
+    #[serde(default)]
+    pub password_hash: PasswordHashConfig,
+    #[serde(default)]
+    pub ntor_session: NTorSessionConfig,
+}
+
+/// argon2id cost parameters, stored passwords hashed with other parameters are rehashed on the next login.
+#[derive(Debug, Deserialize)]
+pub(super) struct PasswordHashConfig {
+    #[serde(default = "PasswordHashConfig::default_memory_kib")]
+    pub memory_kib: u32,
+    #[serde(default = "PasswordHashConfig::default_iterations")]
+    pub iterations: u32,
+    #[serde(default = "PasswordHashConfig::default_parallelism")]
+    pub parallelism: u32,
+}
+
+impl Default for PasswordHashConfig {
+    fn default() -> Self {
+        PasswordHashConfig {
+            memory_kib: Self::default_memory_kib(),
+            iterations: Self::default_iterations(),
+            parallelism: Self::default_parallelism(),
+        }
+    }
+}
+
+impl PasswordHashConfig {
+    // OWASP recommended minimum for argon2id
+    fn default_memory_kib() -> u32 {
+        19 * 1024
+    }
+
+    fn default_iterations() -> u32 {
+        2
+    }
+
+    fn default_parallelism() -> u32 {
+        1
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub(super) struct NTorSessionConfig {
+    /// sessions not used for this long are closed
+    #[serde(default = "NTorSessionConfig::default_idle_ttl_secs")]
+    pub idle_ttl_secs: u64,
+    /// sessions are closed this long after their handshake, however often they are used
+    #[serde(default = "NTorSessionConfig::default_absolute_ttl_secs")]
+    pub absolute_ttl_secs: u64,
+    /// once reached, each new handshake evicts the least recently used session
+    #[serde(default = "NTorSessionConfig::default_max_sessions")]
+    pub max_sessions: usize,
+    /// how often expired sessions are removed in the background
+    #[serde(default = "NTorSessionConfig::default_reap_interval_secs")]
+    pub reap_interval_secs: u64,
+}
+
+impl Default for NTorSessionConfig {
+    fn default() -> Self {
+        NTorSessionConfig {
+            idle_ttl_secs: Self::default_idle_ttl_secs(),
+            absolute_ttl_secs: Self::default_absolute_ttl_secs(),
+            max_sessions: Self::default_max_sessions(),
+            reap_interval_secs: Self::default_reap_interval_secs(),
+        }
+    }
 }
 
+impl NTorSessionConfig {
+    fn default_idle_ttl_secs() -> u64 {
+        30 * 60
+    }
+
+    fn default_absolute_ttl_secs() -> u64 {
+        24 * 60 * 60
+    }
+
+    fn default_max_sessions() -> usize {
+        10_000
+    }
+
+    fn default_reap_interval_secs() -> u64 {
+        60
+    }
+}
+
+
 
+#[derive(Debug, Deserialize)]
+pub(super) struct StorageConfig {
+    #[serde(default)]
+    pub kind: StorageKind,
+    /// database file used by the sqlite backend
+    #[serde(default = "StorageConfig::default_path")]
+    pub path: String,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        StorageConfig {
+            kind: StorageKind::default(),
+            path: Self::default_path(),
+        }
+    }
+}
+
+impl StorageConfig {
+    fn default_path() -> String {
+        "wgp.db".to_string()
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(super) enum StorageKind {
+    /// everything is lost on restart
+    #[default]
+    Memory,
+    Sqlite,
+}
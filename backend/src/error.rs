@@ -0,0 +1,176 @@
+use std::fmt;
+use http::header::CONTENT_TYPE;
+use log::error;
+use pingora::http::StatusCode;
+use serde::{Deserialize, Serialize};
+use crate::format::Format;
+use crate::router::types::{IntoResponse, Response};
+use crate::validate::FieldError;
+
+/// WgpError is an error the server answers a request with.
+/// Each variant has a machine-readable code and maps to an HTTP status, see [`WgpError::code`] and [`WgpError::status`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum WgpError {
+    /// the request body is malformed or could not be read
+    InvalidBody(String),
+    /// a path or query parameter is missing or malformed
+    InvalidParameter(String),
+    /// the request body is well-formed but some of its fields break their validation rules
+    Validation(Vec<FieldError>),
+    /// the request body exceeds the maximum body size
+    PayloadTooLarge,
+    /// the request body is in a format the server does not support, see [`crate::format::Format`]
+    UnsupportedMediaType,
+    /// the client accepts none of the formats the server can answer in
+    NotAcceptable,
+    /// the route does not exist
+    NotFound(String),
+    /// the path exists but does not support the request's method
+    MethodNotAllowed,
+    InvalidCredentials,
+    UsernameTaken,
+    /// the request has no authorization token
+    MissingToken,
+    /// the authorization token is malformed, expired or names no user
+    InvalidToken,
+    UnknownUser,
+    /// the client's nTor public key is not a 32 bytes X25519 key
+    InvalidPublicKey,
+    /// the client advertises no nTor protocol version or AEAD suite the server supports
+    UnsupportedProtocol,
+    /// the nTor session named by the request does not exist or has expired
+    NoNTorSession,
+    /// the nTor session has not derived its keys yet
+    NoSessionKeys,
+    /// the message's sequence number was already accepted, or is too old to tell
+    Replayed,
+    /// the message could not be authenticated or decrypted
+    DecryptFailed,
+    EncryptFailed,
+    /// anything else going wrong on the server's side, whose details are only logged
+    Internal(String),
+}
+
+impl WgpError {
+    /// Returns the machine-readable code of the error, sent in the `code` field of its response body.
+    pub fn code(&self) -> &'static str {
+        match self {
+            WgpError::InvalidBody(_) => "invalid_body",
+            WgpError::InvalidParameter(_) => "invalid_parameter",
+            WgpError::Validation(_) => "validation_failed",
+            WgpError::PayloadTooLarge => "payload_too_large",
+            WgpError::UnsupportedMediaType => "unsupported_media_type",
+            WgpError::NotAcceptable => "not_acceptable",
+            WgpError::NotFound(_) => "not_found",
+            WgpError::MethodNotAllowed => "method_not_allowed",
+            WgpError::InvalidCredentials => "invalid_credentials",
+            WgpError::UsernameTaken => "username_taken",
+            WgpError::MissingToken => "missing_token",
+            WgpError::InvalidToken => "invalid_token",
+            WgpError::UnknownUser => "unknown_user",
+            WgpError::InvalidPublicKey => "invalid_public_key",
+            WgpError::UnsupportedProtocol => "unsupported_protocol",
+            WgpError::NoNTorSession => "no_ntor_session",
+            WgpError::NoSessionKeys => "no_session_keys",
+            WgpError::Replayed => "replayed",
+            WgpError::DecryptFailed => "decrypt_failed",
+            WgpError::EncryptFailed => "encrypt_failed",
+            WgpError::Internal(_) => "internal",
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            WgpError::InvalidBody(_)
+            | WgpError::InvalidParameter(_)
+            | WgpError::InvalidPublicKey
+            | WgpError::UnsupportedProtocol
+            | WgpError::NoNTorSession
+            | WgpError::DecryptFailed => StatusCode::BAD_REQUEST,
+            WgpError::InvalidCredentials
+            | WgpError::MissingToken
+            | WgpError::InvalidToken
+            | WgpError::UnknownUser => StatusCode::UNAUTHORIZED,
+            WgpError::NotFound(_) => StatusCode::NOT_FOUND,
+            WgpError::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+            WgpError::Validation(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            WgpError::UsernameTaken | WgpError::Replayed => StatusCode::CONFLICT,
+            WgpError::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            WgpError::UnsupportedMediaType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            WgpError::NotAcceptable => StatusCode::NOT_ACCEPTABLE,
+            WgpError::NoSessionKeys
+            | WgpError::EncryptFailed
+            | WgpError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl fmt::Display for WgpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WgpError::InvalidBody(err) => write!(f, "invalid request body: {}", err),
+            WgpError::InvalidParameter(err) => write!(f, "{}", err),
+            WgpError::Validation(errors) => {
+                let fields = errors.iter().map(|err| err.field.as_str()).collect::<Vec<_>>().join(", ");
+                write!(f, "invalid fields: {}", fields)
+            }
+            WgpError::PayloadTooLarge => write!(f, "request body too large"),
+            WgpError::UnsupportedMediaType => write!(f, "unsupported media type"),
+            WgpError::NotAcceptable => write!(f, "no acceptable response format"),
+            WgpError::NotFound(what) => write!(f, "{} not found", what),
+            WgpError::MethodNotAllowed => write!(f, "method not allowed"),
+            WgpError::InvalidCredentials => write!(f, "Invalid username or password"),
+            WgpError::UsernameTaken => write!(f, "Username already exists"),
+            WgpError::MissingToken => write!(f, "Unauthorized"),
+            WgpError::InvalidToken => write!(f, "Invalid token"),
+            WgpError::UnknownUser => write!(f, "User does not exist"),
+            WgpError::InvalidPublicKey => write!(f, "invalid nTor public key"),
+            WgpError::UnsupportedProtocol => write!(f, "no nTor protocol version or AEAD suite in common"),
+            WgpError::NoNTorSession => write!(f, "no nTor session found"),
+            WgpError::NoSessionKeys => write!(f, "no nTor session keys"),
+            WgpError::Replayed => write!(f, "nTor message replayed"),
+            WgpError::DecryptFailed => write!(f, "decrypt failed"),
+            WgpError::EncryptFailed => write!(f, "encrypt failed"),
+            WgpError::Internal(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for WgpError {}
+
+/// ErrorResponseBody is the body of error responses, e.g. `{"error":"Invalid token","code":"invalid_token"}`.
+/// Validation errors detail each invalid field, see [`FieldError`].
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ErrorResponseBody {
+    pub error: String,
+    pub code: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub details: Vec<FieldError>,
+}
+
+impl IntoResponse for WgpError {
+    fn into_response(self) -> Response {
+        self.into_response_as(Format::Json)
+    }
+
+    /// Server errors are logged, and answered without their details.
+    fn into_response_as(self, format: Format) -> Response {
+        let status = self.status();
+        let message = if status.is_server_error() {
+            error!("{}", self);
+            "internal server error".to_string()
+        } else {
+            self.to_string()
+        };
+
+        let details = match &self {
+            WgpError::Validation(errors) => errors.clone(),
+            _ => Vec::new(),
+        };
+
+        let body = ErrorResponseBody { error: message, code: self.code().to_string(), details };
+        Response::new(status, Some(format.serialize(&body).unwrap()))
+            .with_header(CONTENT_TYPE, format.content_type())
+            .vary_on_accept()
+    }
+}
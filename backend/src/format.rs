@@ -0,0 +1,290 @@
+use http::header::{ACCEPT, CONTENT_TYPE};
+use http::HeaderValue;
+use pingora::http::RequestHeader;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use crate::error::WgpError;
+
+/// Media type of opaque binary bodies, such as the binary nTor envelope or the content of images.
+pub const OCTET_STREAM: &str = "application/octet-stream";
+
+/// Format is a serialization format of request and response bodies, chosen by content negotiation:
+/// request bodies are read in the format of their `Content-Type`, and responses are written in the
+/// preferred format of the `Accept` header. JSON is the default of both.
+/// On nTor routes, the format applies both to the NTorEncryptMessage envelope and to the body it encrypts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Format {
+    #[default]
+    Json,
+    /// JSON whose byte fields are arrays of numbers rather than base64 strings, see [`base64_bytes`],
+    /// for clients that cannot decode base64, asked for with the `bytes=array` parameter: `application/json; bytes=array`.
+    JsonByteArrays,
+    Cbor,
+    MessagePack,
+}
+
+impl Format {
+    pub fn media_type(self) -> &'static str {
+        match self {
+            Format::Json => "application/json",
+            Format::JsonByteArrays => "application/json; bytes=array",
+            Format::Cbor => "application/cbor",
+            Format::MessagePack => "application/msgpack",
+        }
+    }
+
+    pub fn content_type(self) -> HeaderValue {
+        HeaderValue::from_static(self.media_type())
+    }
+
+    /// Returns the format of a media type, ignoring its parameters such as `charset` but `bytes=array` on JSON.
+    pub fn from_media_type(media_type: &str) -> Option<Format> {
+        let mut parts = media_type.split(';');
+        let essence = parts.next().unwrap_or_default().trim().to_ascii_lowercase();
+        let byte_arrays = parts.any(|param| {
+            param.split_once('=').is_some_and(|(name, value)| {
+                name.trim().eq_ignore_ascii_case("bytes") && value.trim().trim_matches('"').eq_ignore_ascii_case("array")
+            })
+        });
+        let json = if byte_arrays { Format::JsonByteArrays } else { Format::Json };
+
+        match essence.as_str() {
+            "application/json" => Some(json),
+            "application/cbor" => Some(Format::Cbor),
+            "application/msgpack" | "application/x-msgpack" | "application/vnd.msgpack" => Some(Format::MessagePack),
+            _ if essence.starts_with("application/") && essence.ends_with("+json") => Some(json),
+            _ => None,
+        }
+    }
+
+    /// Returns the format of the request body, JSON if it has no `Content-Type`.
+    /// # Errors
+    /// * Returns WgpError::UnsupportedMediaType if the content type is not a supported format.
+    pub fn of_request(request_header: &RequestHeader) -> Result<Format, WgpError> {
+        let Some(content_type) = request_header.headers.get(CONTENT_TYPE) else {
+            return Ok(Format::Json);
+        };
+
+        content_type.to_str().ok()
+            .and_then(Format::from_media_type)
+            .ok_or(WgpError::UnsupportedMediaType)
+    }
+
+    /// Chooses the format of the response from the `Accept` header, by decreasing quality.
+    /// Wildcards and a missing header select the format of the request body, so that clients get back what they send.
+    /// # Errors
+    /// * Returns WgpError::NotAcceptable if the client accepts none of the supported formats.
+    pub fn negotiate(request_header: &RequestHeader) -> Result<Format, WgpError> {
+        Format::negotiate_or(request_header, Format::of_request(request_header).unwrap_or_default())
+    }
+
+    /// Like [`Format::negotiate`], wildcards and a missing header selecting `fallback` instead.
+    /// `application/octet-stream` asks for the binary nTor envelope rather than a format, see `Envelope`,
+    /// so it only selects `fallback` when no format is accepted.
+    pub fn negotiate_or(request_header: &RequestHeader, fallback: Format) -> Result<Format, WgpError> {
+        if !request_header.headers.contains_key(ACCEPT) {
+            return Ok(fallback);
+        }
+
+        let mut octet_stream = false;
+        for media_range in Format::accepted_media_ranges(request_header) {
+            let essence = media_range.split(';').next().unwrap_or_default().trim();
+            if essence == "*/*" || essence.eq_ignore_ascii_case("application/*") {
+                return Ok(fallback);
+            }
+            if essence.eq_ignore_ascii_case(OCTET_STREAM) {
+                octet_stream = true;
+                continue;
+            }
+            if let Some(format) = Format::from_media_type(media_range) {
+                return Ok(format);
+            }
+        }
+
+        if octet_stream {
+            Ok(fallback)
+        } else {
+            Err(WgpError::NotAcceptable)
+        }
+    }
+
+    /// Returns the media ranges of the `Accept` header by decreasing quality, without their quality and the
+    /// accept extensions following it, and without the ones the client refuses with `q=0`.
+    /// Media type parameters such as `bytes=array` are kept.
+    pub fn accepted_media_ranges(request_header: &RequestHeader) -> Vec<&str> {
+        let Some(accept) = request_header.headers.get(ACCEPT).and_then(|accept| accept.to_str().ok()) else {
+            return Vec::new();
+        };
+
+        let mut ranges: Vec<(&str, f32)> = accept.split(',')
+            .map(|range| {
+                let quality_start = range.match_indices(';')
+                    .map(|(index, _)| index)
+                    .find(|&index| range[index + 1..].trim_start().starts_with("q="));
+                let Some(quality_start) = quality_start else {
+                    return (range.trim(), 1.0);
+                };
+
+                let quality = range[quality_start + 1..].trim_start()["q=".len()..]
+                    .split(';').next().unwrap_or_default()
+                    .trim().parse::<f32>().unwrap_or(1.0);
+                (range[..quality_start].trim(), quality)
+            })
+            .filter(|(media_range, quality)| !media_range.is_empty() && *quality > 0.0)
+            .collect();
+        // the sort is stable, so that ranges of equal quality keep the client's order
+        ranges.sort_by(|a, b| b.1.total_cmp(&a.1));
+        ranges.into_iter().map(|(media_range, _)| media_range).collect()
+    }
+
+    pub fn serialize<T: Serialize + ?Sized>(self, value: &T) -> Result<Vec<u8>, String> {
+        match self {
+            Format::Json => serde_json::to_vec(value).map_err(|err| err.to_string()),
+            Format::JsonByteArrays => base64_bytes::as_arrays(|| serde_json::to_vec(value)).map_err(|err| err.to_string()),
+            Format::Cbor => {
+                let mut bytes = Vec::new();
+                ciborium::into_writer(value, &mut bytes).map_err(|err| err.to_string())?;
+                Ok(bytes)
+            }
+            // named, so that fields are maps keyed by their names as in JSON rather than positional arrays
+            Format::MessagePack => rmp_serde::to_vec_named(value).map_err(|err| err.to_string()),
+        }
+    }
+
+    pub fn deserialize<T: DeserializeOwned>(self, bytes: &[u8]) -> Result<T, String> {
+        match self {
+            Format::Json | Format::JsonByteArrays => serde_json::from_slice(bytes).map_err(|err| err.to_string()),
+            Format::Cbor => ciborium::from_reader(bytes).map_err(|err| err.to_string()),
+            Format::MessagePack => rmp_serde::from_slice(bytes).map_err(|err| err.to_string()),
+        }
+    }
+}
+
+/// Serializes byte fields as native byte strings in the binary formats, and in JSON as base64 strings,
+/// or as arrays of numbers for clients asking for [`Format::JsonByteArrays`],
+/// e.g. `#[serde(with = "crate::format::base64_bytes")] pub encrypted: Vec<u8>`.
+/// Every encoding is accepted whatever the media type, so that clients may send either.
+pub mod base64_bytes {
+    use std::cell::Cell;
+    use std::fmt;
+    use base64::Engine;
+    use base64::engine::general_purpose::STANDARD;
+    use serde::de::{Error, SeqAccess, Visitor};
+    use serde::{Deserializer, Serializer};
+
+    thread_local! {
+        static AS_ARRAYS: Cell<bool> = const { Cell::new(false) };
+    }
+
+    /// Runs `serialize` with byte fields written as arrays of numbers in human-readable formats.
+    /// Serializers cannot be told about media type parameters, so the choice is held by the thread for the call.
+    pub(crate) fn as_arrays<R>(serialize: impl FnOnce() -> R) -> R {
+        struct Restore(bool);
+        impl Drop for Restore {
+            fn drop(&mut self) {
+                AS_ARRAYS.with(|as_arrays| as_arrays.set(self.0));
+            }
+        }
+
+        let _restore = Restore(AS_ARRAYS.with(|as_arrays| as_arrays.replace(true)));
+        serialize()
+    }
+
+    pub fn serialize<T: AsRef<[u8]>, S: Serializer>(bytes: &T, serializer: S) -> Result<S::Ok, S::Error> {
+        // serde_json writes byte strings as arrays of numbers
+        if serializer.is_human_readable() && !AS_ARRAYS.with(Cell::get) {
+            serializer.serialize_str(&STANDARD.encode(bytes.as_ref()))
+        } else {
+            serializer.serialize_bytes(bytes.as_ref())
+        }
+    }
+
+    pub fn deserialize<'de, T: TryFrom<Vec<u8>>, D: Deserializer<'de>>(deserializer: D) -> Result<T, D::Error> {
+        let bytes = deserializer.deserialize_any(BytesVisitor)?;
+        let length = bytes.len();
+        T::try_from(bytes).map_err(|_| D::Error::custom(format!("unexpected length {}", length)))
+    }
+
+    struct BytesVisitor;
+
+    impl<'de> Visitor<'de> for BytesVisitor {
+        type Value = Vec<u8>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "a base64 string, a byte string or an array of bytes")
+        }
+
+        fn visit_str<E: Error>(self, value: &str) -> Result<Self::Value, E> {
+            STANDARD.decode(value).map_err(E::custom)
+        }
+
+        fn visit_bytes<E: Error>(self, value: &[u8]) -> Result<Self::Value, E> {
+            Ok(value.to_vec())
+        }
+
+        fn visit_byte_buf<E: Error>(self, value: Vec<u8>) -> Result<Self::Value, E> {
+            Ok(value)
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+            let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or_default());
+            while let Some(byte) = seq.next_element::<u8>()? {
+                bytes.push(byte);
+            }
+            Ok(bytes)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::HeaderValue;
+    use serde::{Deserialize, Serialize};
+    use super::*;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Bytes {
+        #[serde(with = "base64_bytes")]
+        bytes: Vec<u8>,
+    }
+
+    fn accepting(accept: &str) -> RequestHeader {
+        let mut request_header = RequestHeader::build("GET", b"/", None).unwrap();
+        request_header.insert_header(ACCEPT, HeaderValue::from_str(accept).unwrap()).unwrap();
+        request_header
+    }
+
+    #[test]
+    fn json_bytes_are_base64_unless_arrays_are_asked_for() {
+        let value = Bytes { bytes: vec![1, 2, 255] };
+
+        assert_eq!(Format::Json.serialize(&value).unwrap(), br#"{"bytes":"AQL/"}"#);
+        assert_eq!(Format::JsonByteArrays.serialize(&value).unwrap(), br#"{"bytes":[1,2,255]}"#);
+        // the choice does not outlive the call
+        assert_eq!(Format::Json.serialize(&value).unwrap(), br#"{"bytes":"AQL/"}"#);
+    }
+
+    #[test]
+    fn json_bytes_are_read_in_either_encoding() {
+        let value = Bytes { bytes: vec![1, 2, 255] };
+
+        for format in [Format::Json, Format::JsonByteArrays] {
+            assert_eq!(format.deserialize::<Bytes>(br#"{"bytes":[1,2,255]}"#).unwrap(), value);
+            assert_eq!(format.deserialize::<Bytes>(br#"{"bytes":"AQL/"}"#).unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn byte_arrays_are_asked_for_with_a_media_type_parameter() {
+        assert_eq!(Format::from_media_type("application/json"), Some(Format::Json));
+        assert_eq!(Format::from_media_type("application/json; charset=utf-8"), Some(Format::Json));
+        assert_eq!(Format::from_media_type("application/json; bytes=base64"), Some(Format::Json));
+        assert_eq!(Format::from_media_type("application/json; bytes=array"), Some(Format::JsonByteArrays));
+        assert_eq!(Format::from_media_type("application/problem+json;charset=utf-8;Bytes=\"ARRAY\""), Some(Format::JsonByteArrays));
+        assert_eq!(Format::from_media_type(Format::JsonByteArrays.media_type()), Some(Format::JsonByteArrays));
+
+        assert_eq!(Format::negotiate(&accepting("application/json; bytes=array")), Ok(Format::JsonByteArrays));
+        assert_eq!(Format::negotiate(&accepting("application/json; bytes=array; q=0.5, application/cbor")), Ok(Format::Cbor));
+        assert_eq!(Format::negotiate(&accepting("application/json; q=0.9; ext=1, application/json; bytes=array; q=0.5")), Ok(Format::Json));
+    }
+}
@@ -2,8 +2,12 @@ mod proxy;
 mod config;
 mod router;
 mod message;
+mod error;
+mod validate;
+mod format;
 
 use std::env;
+use std::sync::Arc;
 use clap::Parser;
 use env_logger;
 use chrono::Local;
@@ -13,7 +17,10 @@ use std::io::Write;
 
 use pingora::server::configuration::Opt;
 use pingora::server::Server;
+use pingora::services::background::background_service;
 use crate::message::handler::WGPMessageHandler;
+use crate::proxy::cors::Cors;
+use crate::proxy::upstream::UpstreamGroup;
 
 fn log_init(filepath: &String, level: &LevelFilter) {
     let mut target = env_logger::Target::Stdout;
@@ -64,27 +71,53 @@ fn main() {
 
     log_init(&wgp_config.log.path, &wgp_config.log.to_level_filter());
 
-    let msg_handler = WGPMessageHandler::new(wgp_config.handler);
-    let mut router = router::Router::new(msg_handler);
-    router.post("/login".to_string(), Box::new([WGPMessageHandler::ntor_decrypt, WGPMessageHandler::handle_login, WGPMessageHandler::ntor_encrypt]));
-    router.post("/register".to_string(), Box::new([WGPMessageHandler::ntor_decrypt, WGPMessageHandler::handle_register, WGPMessageHandler::ntor_encrypt]));
-    router.get("/profile".to_string(), Box::new([WGPMessageHandler::authentication_middleware, WGPMessageHandler::get_profile, WGPMessageHandler::ntor_encrypt]));
-    router.get("/poems?id={}".to_string(), Box::new([WGPMessageHandler::authentication_middleware, WGPMessageHandler::get_poems, WGPMessageHandler::ntor_encrypt]));
-    router.get("/images?id={}".to_string(), Box::new([WGPMessageHandler::authentication_middleware, WGPMessageHandler::get_images, WGPMessageHandler::ntor_encrypt]));
-    router.post("/ntor_init".to_string(), Box::new([WGPMessageHandler::ntor_init]));
+    let storage = message::db::from_config(&wgp_config.storage);
+    let msg_handler = Arc::new(WGPMessageHandler::new(wgp_config.handler, storage));
+    let session_reaper = msg_handler.session_reaper();
+    let mut router = router::Router::new();
+    router.set_passthrough(wgp_config.upstream.passthrough.clone(), wgp_config.upstream.encrypted);
+    // registered first, so that a local route of the same method and path replaces them
+    for route in &wgp_config.upstream.routes {
+        router.upstream(route.method(), route.path.clone(), route.group.clone(), route.encrypted);
+    }
+    router.post("/ntor_init".to_string(), typed_handlers![msg_handler; WGPMessageHandler::ntor_init]);
+    router.post("/ntor_close".to_string(), handlers![msg_handler; WGPMessageHandler::ntor_decrypt, WGPMessageHandler::ntor_close]);
+
+    // requests and responses are protected with the client's nTor session
+    let mut encrypted = router.group("")
+        .wrap(middleware![msg_handler; WGPMessageHandler::ntor_encrypt])
+        .before(handlers![msg_handler; WGPMessageHandler::ntor_decrypt]);
+    encrypted.post("/login".to_string(), typed_handlers![msg_handler; WGPMessageHandler::handle_login]);
+    encrypted.post("/register".to_string(), typed_handlers![msg_handler; WGPMessageHandler::handle_register]);
 
-    let handler = proxy::handler::ProxyHandler::new(router);
+    // requests carry no body, responses are protected with the client's nTor session
+    let mut authenticated = router.group("")
+        .wrap(middleware![msg_handler; WGPMessageHandler::ntor_encrypt])
+        .before(handlers![msg_handler; WGPMessageHandler::authentication_middleware]);
+    authenticated.get("/profile".to_string(), typed_handlers![msg_handler; WGPMessageHandler::get_profile]);
+    authenticated.get("/poems".to_string(), typed_handlers![msg_handler; WGPMessageHandler::get_poems]);
+    authenticated.get("/poems/{id}".to_string(), typed_handlers![msg_handler; WGPMessageHandler::get_poem]);
+    authenticated.get("/images".to_string(), typed_handlers![msg_handler; WGPMessageHandler::get_images]);
+    authenticated.get("/images/{id}".to_string(), typed_handlers![msg_handler; WGPMessageHandler::get_image]);
+    // streamed as encrypted frames
+    authenticated.get("/images/{id}/content".to_string(), typed_handlers![msg_handler; WGPMessageHandler::get_image_content]);
+
+    let handler = proxy::handler::ProxyHandler::new(router, wgp_config.server.max_body_bytes, Cors::from_config(&wgp_config.cors));
 
     let opt = Opt::parse();
     let mut my_server = Server::new(Some(opt)).unwrap();
     my_server.bootstrap();
 
+    let (upstreams, health_checks) = UpstreamGroup::from_config(&wgp_config.upstream.groups);
+
     let mut my_proxy = pingora::proxy::http_proxy_service(
         &my_server.configuration,
-        proxy::Proxy::new(wgp_config.upstream.host, wgp_config.upstream.port, handler),
+        proxy::Proxy::new(upstreams, handler, msg_handler),
     );
 
     my_proxy.add_tcp(wgp_config.server.address.as_str());
     my_server.add_service(my_proxy);
+    my_server.add_services(health_checks);
+    my_server.add_service(background_service("nTor session reaper", session_reaper));
     my_server.run_forever();
 }
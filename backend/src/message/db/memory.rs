@@ -0,0 +1,115 @@
+use std::collections::HashMap;
+use crate::message::db::{hash_password, seed_images, seed_poems, seed_users, NTorSession, Storage};
+use crate::message::types::other::{Image, Poem, UserMetadata};
+
+/// WGPDatabase keeps everything in memory, so it starts over from the seed data on every restart.
+pub struct WGPDatabase {
+    user_password: HashMap<String, String>,
+    user_metadata: HashMap<String, UserMetadata>,
+    poems: Box<[Poem]>,
+    images: Box<[Image]>,
+    ntor_sessions: HashMap<String, NTorSession>
+}
+
+impl WGPDatabase {
+    pub fn new() -> Self {
+        let mut user_password = HashMap::new();
+        let mut user_metadata = HashMap::new();
+        for (username, password, metadata) in seed_users() {
+            user_password.insert(username.clone(), hash_password(&password));
+            user_metadata.insert(username, metadata);
+        }
+
+        WGPDatabase {
+            user_password,
+            user_metadata,
+            poems: seed_poems().into_boxed_slice(),
+            images: seed_images().into_boxed_slice(),
+            ntor_sessions: HashMap::new()
+        }
+    }
+}
+
+impl Storage for WGPDatabase {
+    fn add_user(&mut self, username: String, password: String, metadata: UserMetadata) -> Result<(), String> {
+        self.user_password.insert(username.clone(), password);
+        self.user_metadata.insert(username, metadata);
+        Ok(())
+    }
+
+    fn get_password(&self, username: &str) -> Option<String> {
+        self.user_password.get(username).cloned()
+    }
+
+    fn update_password(&mut self, username: &str, password: String) -> Result<(), String> {
+        match self.user_password.get_mut(username) {
+            Some(stored) => {
+                *stored = password;
+                Ok(())
+            }
+            None => Err(format!("User {} not found", username)),
+        }
+    }
+
+    fn get_metadata(&self, username: &str) -> Option<UserMetadata> {
+        self.user_metadata.get(username).cloned()
+    }
+
+    fn user_exists(&self, username: &str) -> bool {
+        self.user_password.contains_key(username)
+    }
+
+    fn get_poems(&self) -> Vec<Poem> {
+        self.poems.to_vec()
+    }
+
+    fn get_poem(&self, id: i32) -> Option<Poem> {
+        self.poems.iter().find(|&poem| poem.id == id).cloned()
+    }
+
+    fn get_images(&self) -> Vec<Image> {
+        self.images.to_vec()
+    }
+
+    fn get_image(&self, id: i32) -> Option<Image> {
+        self.images.iter().find(|&image| image.id == id).cloned()
+    }
+
+    fn save_ntor_session(&mut self, session_id: &str, session: NTorSession) -> Result<(), String> {
+        self.ntor_sessions.insert(session_id.to_string(), session);
+        Ok(())
+    }
+
+    fn get_ntor_session(&self, session_id: &str) -> Option<NTorSession> {
+        self.ntor_sessions.get(session_id).cloned()
+    }
+
+    fn remove_ntor_session(&mut self, session_id: &str) -> Result<bool, String> {
+        Ok(self.ntor_sessions.remove(session_id).is_some())
+    }
+
+    fn remove_expired_ntor_sessions(&mut self, idle_before: i64, created_before: i64) -> Result<usize, String> {
+        let count = self.ntor_sessions.len();
+        self.ntor_sessions.retain(|_, session| {
+            session.last_used_at >= idle_before && session.created_at >= created_before
+        });
+        Ok(count - self.ntor_sessions.len())
+    }
+
+    fn evict_ntor_sessions(&mut self, max_sessions: usize) -> Result<usize, String> {
+        let excess = self.ntor_sessions.len().saturating_sub(max_sessions);
+        if excess == 0 {
+            return Ok(0);
+        }
+
+        let mut by_last_use: Vec<(i64, String)> = self.ntor_sessions.iter()
+            .map(|(session_id, session)| (session.last_used_at, session_id.clone()))
+            .collect();
+        by_last_use.sort_unstable();
+
+        for (_, session_id) in by_last_use.into_iter().take(excess) {
+            self.ntor_sessions.remove(&session_id);
+        }
+        Ok(excess)
+    }
+}
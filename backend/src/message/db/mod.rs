@@ -0,0 +1,202 @@
+pub(crate) mod memory;
+pub(crate) mod sqlite;
+
+use std::path::PathBuf;
+use log::debug;
+use crate::config::{NTorSessionConfig, PasswordHashConfig, StorageConfig, StorageKind};
+use crate::message::ntor::server::{Server as nTorServer};
+use crate::message::types::other::{Image, Poem, UserMetadata};
+use crate::message::db::memory::WGPDatabase;
+use crate::message::db::sqlite::SqliteDatabase;
+use crate::message::password::Argon2Hasher;
+
+/// Storage abstracts where users, their metadata, the served content and nTor sessions are kept,
+/// so that the message handler works the same on top of any backend.
+pub trait Storage: Send {
+    fn add_user(&mut self, username: String, password: String, metadata: UserMetadata) -> Result<(), String>;
+    /// `password` is always a password hash, never the plaintext
+    fn get_password(&self, username: &str) -> Option<String>;
+    fn update_password(&mut self, username: &str, password: String) -> Result<(), String>;
+    fn get_metadata(&self, username: &str) -> Option<UserMetadata>;
+    fn user_exists(&self, username: &str) -> bool;
+    fn get_poems(&self) -> Vec<Poem>;
+    fn get_poem(&self, id: i32) -> Option<Poem>;
+    /// the content of images is left empty, it is read from file with read_image_content
+    fn get_images(&self) -> Vec<Image>;
+    fn get_image(&self, id: i32) -> Option<Image>;
+    fn save_ntor_session(&mut self, session_id: &str, session: NTorSession) -> Result<(), String>;
+    fn get_ntor_session(&self, session_id: &str) -> Option<NTorSession>;
+    /// returns whether the session existed
+    fn remove_ntor_session(&mut self, session_id: &str) -> Result<bool, String>;
+    /// removes sessions last used before `idle_before` or created before `created_before`,
+    /// both in unix seconds, and returns how many were removed
+    fn remove_expired_ntor_sessions(&mut self, idle_before: i64, created_before: i64) -> Result<usize, String>;
+    /// removes the least recently used sessions until at most `max_sessions` are left,
+    /// and returns how many were removed
+    fn evict_ntor_sessions(&mut self, max_sessions: usize) -> Result<usize, String>;
+}
+
+/// An nTor session together with the unix timestamps it is expired by.
+#[derive(Clone)]
+pub struct NTorSession {
+    pub server: nTorServer,
+    pub created_at: i64,
+    pub last_used_at: i64,
+}
+
+impl NTorSession {
+    pub fn new(server: nTorServer, now: i64) -> Self {
+        NTorSession {
+            server,
+            created_at: now,
+            last_used_at: now,
+        }
+    }
+
+    pub fn is_expired(&self, config: &NTorSessionConfig, now: i64) -> bool {
+        let (idle_before, created_before) = expiry_cutoffs(config, now);
+        self.last_used_at < idle_before || self.created_at < created_before
+    }
+}
+
+/// Returns the (last used, created) timestamps before which sessions are expired at `now`.
+pub fn expiry_cutoffs(config: &NTorSessionConfig, now: i64) -> (i64, i64) {
+    (
+        now.saturating_sub(config.idle_ttl_secs as i64),
+        now.saturating_sub(config.absolute_ttl_secs as i64),
+    )
+}
+
+/// Opens the storage backend selected in the configuration.
+/// # Panics
+/// * If the on-disk database cannot be opened or migrated.
+pub fn from_config(config: &StorageConfig) -> Box<dyn Storage> {
+    match config.kind {
+        StorageKind::Memory => Box::new(WGPDatabase::new()),
+        StorageKind::Sqlite => Box::new(SqliteDatabase::open(&config.path).expect("Failed to open database")),
+    }
+}
+
+/// Returns the path of the file holding the content of an image.
+pub fn image_path(image: &Image) -> PathBuf {
+    let abs_path = format!("{}/{}/{}", env!("CARGO_MANIFEST_DIR"), image.file_path, image.file_name);
+    let mut path = PathBuf::from("images");
+    path.push(&abs_path);
+    path
+}
+
+/// Reads the content of an image from its file, without blocking the async runtime.
+pub async fn read_image_content(image: &Image) -> Result<Vec<u8>, String> {
+    let path = image_path(image);
+
+    match tokio::fs::read(&path).await {
+        Ok(image_data) => {
+            debug!("Read {} bytes from {}", image_data.len(), path.display());
+            Ok(image_data)
+        }
+        Err(err) => {
+            Err(format!("Failed to open image file {}: {}", image.file_path, err))
+        }
+    }
+}
+
+/// Hashes a password with the default cost parameters, for data written by the storage itself.
+/// Passwords using other parameters than the configured ones are rehashed on login.
+fn hash_password(password: &str) -> String {
+    Argon2Hasher::new(&PasswordHashConfig::default()).hash(password).expect("Failed to hash password")
+}
+
+/// Users every new database starts with, as (username, plaintext password, metadata).
+/// The seed is kept as it shipped, storages hash the passwords when they load it,
+/// e.g. SQLite in its `hash_plaintext_passwords` migration.
+fn seed_users() -> Vec<(String, String, UserMetadata)> {
+    vec![
+        ("tester".to_string(), "1234".to_string(), UserMetadata {
+            username: "tester".to_string(),
+            title: "AI Assistant by OpenAI".to_string(),
+            avatar: "https://upload.wikimedia.org/wikipedia/commons/0/04/ChatGPT_logo.svg".to_string(),
+            bio: "ChatGPT is a language model designed to assist with writing, coding, learning, and more. \
+            Trained on a wide range of data, it aims to provide accurate, clear, and human-like responses to support users in diverse tasks.".to_string(),
+            email: "Not applicable 😊".to_string(),
+            location: "The Cloud ☁️".to_string(),
+            website: "https://openai.com/chatgpt".to_string(),
+        }),
+    ]
+}
+
+/// Poems every new database starts with.
+fn seed_poems() -> Vec<Poem> {
+    vec![
+        Poem {
+            id: 1,
+            title: "The Road Not Taken".to_string(),
+            author: "Robert Frost".to_string(),
+            content: "Two roads diverged in a yellow wood,\nAnd sorry I could not travel both...".to_string(),
+        },
+        Poem {
+            id: 2,
+            title: "Still I Rise".to_string(),
+            author: "Maya Angelou".to_string(),
+            content: "You may write me down in history\nWith your bitter, twisted lies...".to_string(),
+        },
+        Poem {
+            id: 3,
+            title: "Ozymandias".to_string(),
+            author: "Percy Bysshe Shelley".to_string(),
+            content: "I met a traveller from an antique land\nWho said—“Two vast and trunkless legs of stone...".to_string(),
+        },
+        Poem {
+            id: 4,
+            title: "If—".to_string(),
+            author: "Rudyard Kipling".to_string(),
+            content: "If you can keep your head when all about you\nAre losing theirs and blaming it on you...".to_string(),
+        },
+        Poem {
+            id: 5,
+            title: "Annabel Lee".to_string(),
+            author: "Edgar Allan Poe".to_string(),
+            content: "It was many and many a year ago,\nIn a kingdom by the sea...".to_string(),
+        }
+    ]
+}
+
+/// Images every new database starts with, their content is read from file on demand.
+fn seed_images() -> Vec<Image> {
+    vec![
+        Image {
+            id: 1,
+            name: "Sample Image 1".to_string(),
+            file_path: "src/message/images".to_string(),
+            file_name: "sample1.jpeg".to_string(),
+            content: vec![],
+        },
+        Image {
+            id: 2,
+            name: "Sample Image 2".to_string(),
+            file_path: "src/message/images".to_string(),
+            file_name: "sample2.jpeg".to_string(),
+            content: vec![],
+        },
+        Image {
+            id: 3,
+            name: "Sample Image 3".to_string(),
+            file_path: "src/message/images".to_string(),
+            file_name: "sample3.jpeg".to_string(),
+            content: vec![],
+        },
+        Image {
+            id: 4,
+            name: "Sample Image 4".to_string(),
+            file_path: "src/message/images".to_string(),
+            file_name: "sample4.jpeg".to_string(),
+            content: vec![],
+        },
+        Image {
+            id: 5,
+            name: "Sample Image 5".to_string(),
+            file_path: "src/message/images".to_string(),
+            file_name: "sample5.jpeg".to_string(),
+            content: vec![],
+        },
+    ]
+}
@@ -0,0 +1,370 @@
+use log::{error, info};
+use rusqlite::{params, Connection, OptionalExtension, Transaction};
+use crate::message::db::{hash_password, seed_images, seed_poems, seed_users, NTorSession, Storage};
+use crate::message::ntor::common::{AeadSuite, SessionKeys};
+use crate::message::ntor::replay::ReplayWindow;
+use crate::message::ntor::server::{Server as nTorServer};
+use crate::message::types::other::{Image, Poem, UserMetadata};
+
+/// A schema migration, applied at most once inside its own transaction.
+type Migration = fn(&Transaction) -> rusqlite::Result<()>;
+
+/// Migrations in the order they are applied. The index of the last applied migration + 1
+/// is kept in `PRAGMA user_version`, so new migrations must only ever be appended.
+const MIGRATIONS: &[Migration] = &[
+    create_tables,
+    insert_seed_data,
+    hash_plaintext_passwords,
+    store_ntor_session_keys,
+    add_ntor_sequencing,
+    add_ntor_session_timestamps,
+    add_ntor_session_protocol,
+];
+
+fn create_tables(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE users (
+            username TEXT PRIMARY KEY,
+            password TEXT NOT NULL
+        );
+        CREATE TABLE user_metadata (
+            username TEXT PRIMARY KEY REFERENCES users(username),
+            title TEXT NOT NULL,
+            avatar TEXT NOT NULL,
+            bio TEXT NOT NULL,
+            email TEXT NOT NULL,
+            location TEXT NOT NULL,
+            website TEXT NOT NULL
+        );
+        CREATE TABLE poems (
+            id INTEGER PRIMARY KEY,
+            title TEXT NOT NULL,
+            author TEXT NOT NULL,
+            content TEXT NOT NULL
+        );
+        CREATE TABLE images (
+            id INTEGER PRIMARY KEY,
+            name TEXT NOT NULL,
+            file_path TEXT NOT NULL,
+            file_name TEXT NOT NULL
+        );
+        CREATE TABLE ntor_sessions (
+            session_id TEXT PRIMARY KEY,
+            server_id TEXT NOT NULL,
+            shared_secret BLOB NOT NULL
+        );",
+    )
+}
+
+fn insert_seed_data(tx: &Transaction) -> rusqlite::Result<()> {
+    for (username, password, metadata) in seed_users() {
+        insert_user(tx, &username, &password, &metadata)?;
+    }
+
+    for poem in seed_poems() {
+        tx.execute(
+            "INSERT INTO poems (id, title, author, content) VALUES (?1, ?2, ?3, ?4)",
+            params![poem.id, poem.title, poem.author, poem.content],
+        )?;
+    }
+
+    for image in seed_images() {
+        tx.execute(
+            "INSERT INTO images (id, name, file_path, file_name) VALUES (?1, ?2, ?3, ?4)",
+            params![image.id, image.name, image.file_path, image.file_name],
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Passwords used to be stored in plaintext, hash them in place.
+fn hash_plaintext_passwords(tx: &Transaction) -> rusqlite::Result<()> {
+    let users: Vec<(String, String)> = {
+        let mut stmt = tx.prepare("SELECT username, password FROM users WHERE password NOT LIKE '$argon2%'")?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        rows.collect::<rusqlite::Result<_>>()?
+    };
+
+    for (username, password) in users {
+        tx.execute(
+            "UPDATE users SET password = ?1 WHERE username = ?2",
+            params![hash_password(&password), username],
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Sessions used to store the raw shared secret, they now store the derived session keys.
+/// Existing sessions are dropped, clients will run a new handshake.
+fn store_ntor_session_keys(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "DROP TABLE ntor_sessions;
+        CREATE TABLE ntor_sessions (
+            session_id TEXT PRIMARY KEY,
+            server_id TEXT NOT NULL,
+            session_keys BLOB NOT NULL
+        );",
+    )
+}
+
+/// Sequence numbers are stored as the bits of an i64, as sqlite integers are signed.
+fn add_ntor_sequencing(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "ALTER TABLE ntor_sessions ADD COLUMN send_sequence INTEGER NOT NULL DEFAULT 0;
+        ALTER TABLE ntor_sessions ADD COLUMN replay_highest INTEGER NOT NULL DEFAULT 0;
+        ALTER TABLE ntor_sessions ADD COLUMN replay_bitmap INTEGER NOT NULL DEFAULT 0;",
+    )
+}
+
+/// Sessions from before timestamps were kept count as created and last used at the epoch,
+/// so they expire on the first reap.
+fn add_ntor_session_timestamps(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "ALTER TABLE ntor_sessions ADD COLUMN created_at INTEGER NOT NULL DEFAULT 0;
+        ALTER TABLE ntor_sessions ADD COLUMN last_used_at INTEGER NOT NULL DEFAULT 0;
+        CREATE INDEX ntor_sessions_last_used_at ON ntor_sessions (last_used_at);",
+    )
+}
+
+/// Sessions from before the protocol was negotiated used version 1 with AES-256-GCM.
+fn add_ntor_session_protocol(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "ALTER TABLE ntor_sessions ADD COLUMN protocol_version INTEGER NOT NULL DEFAULT 1;
+        ALTER TABLE ntor_sessions ADD COLUMN aead_suite TEXT NOT NULL DEFAULT 'aes-256-gcm';",
+    )
+}
+
+fn insert_user(conn: &Connection, username: &str, password: &str, metadata: &UserMetadata) -> rusqlite::Result<()> {
+    conn.execute(
+        "INSERT INTO users (username, password) VALUES (?1, ?2)",
+        params![username, password],
+    )?;
+    conn.execute(
+        "INSERT INTO user_metadata (username, title, avatar, bio, email, location, website)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        params![username, metadata.title, metadata.avatar, metadata.bio, metadata.email, metadata.location, metadata.website],
+    )?;
+    Ok(())
+}
+
+/// SqliteDatabase persists everything in an embedded SQLite file, so registrations and nTor sessions survive restarts.
+pub struct SqliteDatabase {
+    conn: Connection,
+}
+
+impl SqliteDatabase {
+    /// Opens (or creates) the database at `path` and brings its schema up to date.
+    pub fn open(path: &str) -> rusqlite::Result<Self> {
+        let mut conn = Connection::open(path)?;
+        Self::migrate(&mut conn)?;
+        Ok(SqliteDatabase { conn })
+    }
+
+    fn migrate(conn: &mut Connection) -> rusqlite::Result<()> {
+        let version: usize = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+
+        for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            let tx = conn.transaction()?;
+            migration(&tx)?;
+            tx.pragma_update(None, "user_version", index + 1)?;
+            tx.commit()?;
+            info!("Applied database migration {}", index + 1);
+        }
+
+        Ok(())
+    }
+
+    fn image_from_row(row: &rusqlite::Row) -> rusqlite::Result<Image> {
+        Ok(Image {
+            id: row.get(0)?,
+            name: row.get(1)?,
+            file_path: row.get(2)?,
+            file_name: row.get(3)?,
+            content: vec![],
+        })
+    }
+
+    fn poem_from_row(row: &rusqlite::Row) -> rusqlite::Result<Poem> {
+        Ok(Poem {
+            id: row.get(0)?,
+            title: row.get(1)?,
+            author: row.get(2)?,
+            content: row.get(3)?,
+        })
+    }
+
+    fn query_all<V>(&self, sql: &str, from_row: fn(&rusqlite::Row) -> rusqlite::Result<V>) -> rusqlite::Result<Vec<V>> {
+        let mut stmt = self.conn.prepare(sql)?;
+        let rows = stmt.query_map([], from_row)?;
+        rows.collect()
+    }
+
+    /// Logs a query error and treats it as an empty result, as the storage getters cannot fail.
+    fn log_error<V: Default>(result: rusqlite::Result<V>) -> V {
+        result.unwrap_or_else(|err| {
+            error!("database error: {err}");
+            V::default()
+        })
+    }
+}
+
+impl Storage for SqliteDatabase {
+    fn add_user(&mut self, username: String, password: String, metadata: UserMetadata) -> Result<(), String> {
+        let tx = self.conn.transaction().map_err(|err| err.to_string())?;
+        insert_user(&tx, &username, &password, &metadata).map_err(|err| err.to_string())?;
+        tx.commit().map_err(|err| err.to_string())
+    }
+
+    fn get_password(&self, username: &str) -> Option<String> {
+        Self::log_error(self.conn.query_row(
+            "SELECT password FROM users WHERE username = ?1",
+            params![username],
+            |row| row.get(0),
+        ).optional())
+    }
+
+    fn update_password(&mut self, username: &str, password: String) -> Result<(), String> {
+        let updated = self.conn.execute(
+            "UPDATE users SET password = ?1 WHERE username = ?2",
+            params![password, username],
+        ).map_err(|err| err.to_string())?;
+
+        if updated == 0 {
+            return Err(format!("User {} not found", username));
+        }
+        Ok(())
+    }
+
+    fn get_metadata(&self, username: &str) -> Option<UserMetadata> {
+        Self::log_error(self.conn.query_row(
+            "SELECT username, title, avatar, bio, email, location, website FROM user_metadata WHERE username = ?1",
+            params![username],
+            |row| Ok(UserMetadata {
+                username: row.get(0)?,
+                title: row.get(1)?,
+                avatar: row.get(2)?,
+                bio: row.get(3)?,
+                email: row.get(4)?,
+                location: row.get(5)?,
+                website: row.get(6)?,
+            }),
+        ).optional())
+    }
+
+    fn user_exists(&self, username: &str) -> bool {
+        self.get_password(username).is_some()
+    }
+
+    fn get_poems(&self) -> Vec<Poem> {
+        Self::log_error(self.query_all("SELECT id, title, author, content FROM poems ORDER BY id", Self::poem_from_row))
+    }
+
+    fn get_poem(&self, id: i32) -> Option<Poem> {
+        Self::log_error(self.conn.query_row(
+            "SELECT id, title, author, content FROM poems WHERE id = ?1",
+            params![id],
+            Self::poem_from_row,
+        ).optional())
+    }
+
+    fn get_images(&self) -> Vec<Image> {
+        Self::log_error(self.query_all("SELECT id, name, file_path, file_name FROM images ORDER BY id", Self::image_from_row))
+    }
+
+    fn get_image(&self, id: i32) -> Option<Image> {
+        Self::log_error(self.conn.query_row(
+            "SELECT id, name, file_path, file_name FROM images WHERE id = ?1",
+            params![id],
+            Self::image_from_row,
+        ).optional())
+    }
+
+    fn save_ntor_session(&mut self, session_id: &str, session: NTorSession) -> Result<(), String> {
+        let server = &session.server;
+        let Some(session_keys) = server.get_session_keys() else {
+            return Err("nTor session has no session keys".to_string());
+        };
+
+        let (replay_highest, replay_bitmap) = server.get_replay_window().parts();
+        self.conn.execute(
+            "INSERT OR REPLACE INTO ntor_sessions
+             (session_id, server_id, session_keys, send_sequence, replay_highest, replay_bitmap, created_at, last_used_at,
+              protocol_version, aead_suite)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+            params![
+                session_id,
+                server.get_certificate().server_id,
+                session_keys.to_bytes(),
+                server.get_send_sequence() as i64,
+                replay_highest as i64,
+                replay_bitmap as i64,
+                session.created_at,
+                session.last_used_at,
+                server.get_protocol_version(),
+                server.get_suite().name(),
+            ],
+        ).map(|_| ()).map_err(|err| err.to_string())
+    }
+
+    fn get_ntor_session(&self, session_id: &str) -> Option<NTorSession> {
+        let session = Self::log_error(self.conn.query_row(
+            "SELECT server_id, session_keys, send_sequence, replay_highest, replay_bitmap, created_at, last_used_at,
+                    protocol_version, aead_suite
+             FROM ntor_sessions WHERE session_id = ?1",
+            params![session_id],
+            |row| Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, Vec<u8>>(1)?,
+                row.get::<_, i64>(2)? as u64,
+                ReplayWindow::from_parts(row.get::<_, i64>(3)? as u64, row.get::<_, i64>(4)? as u64),
+                row.get::<_, i64>(5)?,
+                row.get::<_, i64>(6)?,
+                row.get::<_, u16>(7)?,
+                row.get::<_, String>(8)?,
+            )),
+        ).optional());
+
+        let (server_id, session_keys, send_sequence, replay_window, created_at, last_used_at, version, suite) = session?;
+        let Some(suite) = AeadSuite::from_name(&suite) else {
+            error!("unknown AEAD suite {} for nTor session {}", suite, session_id);
+            return None;
+        };
+        match SessionKeys::from_bytes(&session_keys) {
+            Some(session_keys) => {
+                let mut server = nTorServer::from_session_keys(server_id, session_keys);
+                server.restore_sequencing(send_sequence, replay_window);
+                server.set_protocol(version, suite);
+                Some(NTorSession { server, created_at, last_used_at })
+            }
+            None => {
+                error!("invalid session keys for nTor session {}", session_id);
+                None
+            }
+        }
+    }
+
+    fn remove_ntor_session(&mut self, session_id: &str) -> Result<bool, String> {
+        self.conn.execute("DELETE FROM ntor_sessions WHERE session_id = ?1", params![session_id])
+            .map(|removed| removed > 0)
+            .map_err(|err| err.to_string())
+    }
+
+    fn remove_expired_ntor_sessions(&mut self, idle_before: i64, created_before: i64) -> Result<usize, String> {
+        self.conn.execute(
+            "DELETE FROM ntor_sessions WHERE last_used_at < ?1 OR created_at < ?2",
+            params![idle_before, created_before],
+        ).map_err(|err| err.to_string())
+    }
+
+    fn evict_ntor_sessions(&mut self, max_sessions: usize) -> Result<usize, String> {
+        self.conn.execute(
+            "DELETE FROM ntor_sessions WHERE session_id IN (
+                SELECT session_id FROM ntor_sessions ORDER BY last_used_at
+                LIMIT max(0, (SELECT count(*) FROM ntor_sessions) - ?1)
+            )",
+            params![max_sessions as i64],
+        ).map_err(|err| err.to_string())
+    }
+}
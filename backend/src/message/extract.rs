@@ -0,0 +1,33 @@
+use crate::error::WgpError;
+use crate::message::types::RequestBodyTrait;
+use crate::router::extract::FromContext;
+use crate::router::types::ContextTrait;
+use crate::validate::Validator;
+
+/// Body parses the buffered request body, already decrypted on nTor routes, in the format of the request,
+/// and checks its validation rules, see `RequestBodyTrait::validate`.
+pub struct Body<T>(pub T);
+
+impl<T: RequestBodyTrait + Send> FromContext for Body<T> {
+    fn from_context(ctx: &dyn ContextTrait) -> Result<Self, WgpError> {
+        let format = ctx.request_format()?;
+        let body = T::from_bytes_as(ctx.get_request_body(), format).map_err(WgpError::InvalidBody)?;
+
+        let mut validator = Validator::new();
+        body.validate(&mut validator);
+        validator.finish()?;
+        Ok(Body(*body))
+    }
+}
+
+/// AuthUser is the name of the user authenticated by `WGPMessageHandler::authentication_middleware`,
+/// which must run before the handler.
+pub struct AuthUser(pub String);
+
+impl FromContext for AuthUser {
+    fn from_context(ctx: &dyn ContextTrait) -> Result<Self, WgpError> {
+        ctx.get("username").cloned()
+            .map(AuthUser)
+            .ok_or_else(|| WgpError::Internal("username not found in context".to_string()))
+    }
+}
@@ -1,135 +1,178 @@
+use std::ops::Range;
 use std::string::ToString;
 use std::sync::{Arc, Mutex, MutexGuard};
+use async_trait::async_trait;
+use http::header::CONTENT_TYPE;
+use http::HeaderValue;
 use log::{debug, error};
-use pingora::http::StatusCode;
-use crate::config::HandlerConfig;
+use pingora::http::{RequestHeader, StatusCode};
+use crate::config::{HandlerConfig, NTorSessionConfig};
+use crate::error::WgpError;
+use crate::format::{Format, OCTET_STREAM};
 use crate::message::types::{RequestBodyTrait, ResponseBodyTrait, NTorEncryptMessage};
-use crate::message::types::request::{LoginRequestBody, RegisterRequestBody, NTorInitRequestBody};
-use crate::message::types::response::{ErrorResponseBody, GetProfileResponse, LoginResponseBody, RegisterResponseBody, GetPoemsResponse, GetPoemResponse, GetImageResponse, GetImagesResponse, NTorInitResponse};
+use crate::message::types::request::{IdParams, ListParams, LoginRequestBody, RegisterRequestBody, NTorInitRequestBody};
+use crate::message::types::response::{GetProfileResponse, LoginResponseBody, RegisterResponseBody, GetPoemsResponse, GetPoemResponse, GetImageResponse, GetImagesResponse, NTorInitResponse, NTorCloseResponse};
 use crate::message::types::other::{UserMetadata};
-use crate::message::db::WGPDatabase;
+use crate::message::extract::{AuthUser, Body};
+use crate::message::db::{image_path, read_image_content, NTorSession, Storage};
+use crate::message::reaper::SessionReaper;
+use crate::message::password::Argon2Hasher;
 use crate::message::ntor::server::{Server as nTorServer};
-use crate::message::ntor::common::{InitSessionMessage};
-use crate::message::utils::{create_jwt_token, new_nTor_session_id, string_to_array32, verify_jwt_token};
-use crate::router::types::{ContextTrait, Response};
+use crate::message::ntor::common::{associated_data, frame_associated_data, Direction, InitSessionMessage};
+use crate::message::ntor::envelope::Envelope;
+use crate::message::utils::{create_jwt_token, new_nTor_session_id, string_to_array32, unix_timestamp, verify_jwt_token};
+use crate::proxy::UpstreamCipher;
+use crate::router::body::{BodyDecoder, BodyStream, FileStream};
+use crate::router::extract::{Params, QueryParams};
+use crate::router::middleware::Next;
+use crate::router::types::{ContextTrait, IntoResponse, Response};
 
 pub struct WGPMessageHandler {
     config: HandlerConfig,
     ntor_static_secret: [u8; 32],
     jwt_secret: [u8; 32],
+    // hashing runs on the blocking thread pool, which needs shared ownership of the hasher
+    password_hasher: Arc<Argon2Hasher>,
     // use std::sync::Mutex to make db mutable without requiring WGPMessageHandler itself to be mutable,
     // and use an Arc if we need shared ownership across threads.
-    db: Arc<Mutex<WGPDatabase>>,
+    db: Arc<Mutex<Box<dyn Storage>>>,
 }
 
 impl WGPMessageHandler {
-    pub fn new(config: HandlerConfig) -> Self {
+    pub fn new(config: HandlerConfig, db: Box<dyn Storage>) -> Self {
         let ntor_secret = string_to_array32(config.ntor_static_secret.clone()).unwrap();
         let jwt_secret = string_to_array32(config.jwt_secret.clone()).unwrap();
+        let password_hasher = Arc::new(Argon2Hasher::new(&config.password_hash));
 
         WGPMessageHandler {
             config,
             ntor_static_secret: ntor_secret,
             jwt_secret,
-            db: Arc::new(Mutex::new(WGPDatabase::new())),
+            password_hasher,
+            db: Arc::new(Mutex::new(db)),
         }
     }
 
-    fn get_db(&self) -> MutexGuard<'_, WGPDatabase> {
+    /// Returns the background service removing expired nTor sessions, which must be added to the server.
+    pub fn session_reaper(&self) -> SessionReaper {
+        SessionReaper::new(self.db.clone(), self.config.ntor_session.clone())
+    }
+
+    fn get_db(&self) -> MutexGuard<'_, Box<dyn Storage>> {
         self.db.lock().unwrap()
     }
 
-    fn parse_request_body<T: RequestBodyTrait>(data: &Vec<u8>) -> (Option<Box<T>>, Option<ErrorResponseBody>, StatusCode) {
-        match T::from_bytes(data.clone()) {
-            Ok(body) => (Some(body), None, StatusCode::OK),
-            Err(e) => {
-                (None, Some(ErrorResponseBody {
-                    error: e.to_string(),
-                }), StatusCode::BAD_REQUEST)
-            }
-        }
+    /// Runs `f` with the locked storage on the blocking thread pool,
+    /// so that slow queries don't stall the proxy's worker threads.
+    /// Everything `f` does happens under a single lock.
+    async fn with_db<R, F>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut dyn Storage) -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        run_with_db(self.db.clone(), f).await
     }
 
-    pub fn handle_login(&self, ctx: &mut dyn ContextTrait) -> Response {
-        let data = ctx.get_request_body();
-        let (body, error, status) = Self::parse_request_body::<LoginRequestBody>(data);
-        if status != StatusCode::OK {
-            return Response::new(status, error.map(|e| e.to_bytes()));
-        }
+    /// Runs `f` with the password hasher on the blocking thread pool, as hashing is deliberately slow.
+    async fn with_hasher<R, F>(&self, f: F) -> R
+    where
+        F: FnOnce(&Argon2Hasher) -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let hasher = self.password_hasher.clone();
+        tokio::task::spawn_blocking(move || f(&hasher))
+            .await
+            .expect("password hashing task panicked")
+    }
 
-        let request_body = body.unwrap(); // Unwrap the Option, safe because we checked status
+    fn parse_request_body<T: RequestBodyTrait>(data: &[u8], format: Format) -> Result<Box<T>, WgpError> {
+        T::from_bytes_as(data, format).map_err(WgpError::InvalidBody)
+    }
 
-        if let Some(password) = self.get_db().get_password(&request_body.username) {
-            if *password == request_body.password {
-                return Response::new(
-                    StatusCode::OK,
-                    Some(LoginResponseBody {
-                        token: create_jwt_token(request_body.username, self.jwt_secret),
-                    }.to_bytes()),
-                );
-            }
-        }
+    pub async fn handle_login(&self, Body(body): Body<LoginRequestBody>) -> Result<LoginResponseBody, WgpError> {
+        let LoginRequestBody { username, password } = body;
+
+        // the db lock is released before hashing, so that slow verifications don't block other requests
+        let lookup = username.clone();
+        let stored_hash = self.with_db(move |db| db.get_password(&lookup)).await;
+
+        if let Some(stored_hash) = stored_hash {
+            // transparently upgrade hashes created with other cost parameters
+            let (verified, rehashed) = self.with_hasher(move |hasher| {
+                if !hasher.verify(&password, &stored_hash) {
+                    return (false, None);
+                }
+                (true, hasher.needs_rehash(&stored_hash).then(|| hasher.hash(&password)))
+            }).await;
+
+            if verified {
+                if let Some(rehashed) = rehashed {
+                    let user = username.clone();
+                    let result = match rehashed {
+                        Ok(hash) => self.with_db(move |db| db.update_password(&user, hash)).await,
+                        Err(err) => Err(err),
+                    };
+                    if let Err(err) = result {
+                        error!("unable to rehash password: {}", err);
+                    }
+                }
 
-        Response::new(
-            StatusCode::BAD_REQUEST,
-            Some(ErrorResponseBody {
-                error: "Invalid username or password".to_string(),
-            }.to_bytes()),
-        )
-    }
-
-    pub fn handle_register(&self, ctx: &mut dyn ContextTrait) -> Response {
-        let data = ctx.get_request_body();
-        let (body, error, status) = Self::parse_request_body::<RegisterRequestBody>(data);
-        if status != StatusCode::OK {
-            return Response::new(
-                status,
-                error.map(|e| e.to_bytes()),
-            );
+                return Ok(LoginResponseBody {
+                    token: create_jwt_token(username, self.jwt_secret),
+                });
+            }
+        } else {
+            self.with_hasher(move |hasher| hasher.verify_dummy(&password)).await;
         }
 
-        let request_body = body.unwrap(); // Unwrap the Option, safe because we checked status
+        Err(WgpError::InvalidCredentials)
+    }
 
-        let mut db = self.get_db();
-        if db.user_exists(&request_body.username) {
-            return Response::new(
-                StatusCode::BAD_REQUEST,
-                Some(ErrorResponseBody {
-                    error: "Username already exists".to_string(),
-                }.to_bytes()),
-            );
+    pub async fn handle_register(&self, Body(request_body): Body<RegisterRequestBody>) -> Result<RegisterResponseBody, WgpError> {
+        // checked before spending a password hash, and again when adding the user in case it registered meanwhile
+        let username = request_body.username.clone();
+        if self.with_db(move |db| db.user_exists(&username)).await {
+            return Err(WgpError::UsernameTaken);
         }
 
-        db.add_user(request_body.username.clone(), request_body.password.clone(), UserMetadata {
-            username: request_body.username.clone(),
-            title: "".to_string(),
-            avatar: "".to_string(),
-            bio: "".to_string(),
-            email: "".to_string(),
-            location: "".to_string(),
-            website: "".to_string(),
-        });
+        let password = request_body.password.clone();
+        let password_hash = self.with_hasher(move |hasher| hasher.hash(&password)).await
+            .map_err(|err| WgpError::Internal(format!("unable to hash password: {}", err)))?;
 
-        Response::new(
-            StatusCode::OK,
-            Some(RegisterResponseBody {
+        let username = request_body.username.clone();
+        let result = self.with_db(move |db| {
+            if db.user_exists(&username) {
+                return Ok(false);
+            }
+
+            db.add_user(username.clone(), password_hash, UserMetadata {
+                username,
+                title: "".to_string(),
+                avatar: "".to_string(),
+                bio: "".to_string(),
+                email: "".to_string(),
+                location: "".to_string(),
+                website: "".to_string(),
+            }).map(|_| true)
+        }).await;
+
+        match result {
+            Ok(true) => Ok(RegisterResponseBody {
                 success: true,
                 message: "User registered successfully".to_string(),
-            }.to_bytes()),
-        )
+            }),
+            Ok(false) => Err(WgpError::UsernameTaken),
+            Err(err) => Err(WgpError::Internal(format!("unable to add user: {}", err))),
+        }
     }
 
-    pub fn authentication_middleware(&self, ctx: &mut dyn ContextTrait) -> Response {
+    pub async fn authentication_middleware(&self, ctx: &mut dyn ContextTrait) -> Response {
         let token = ctx.request_header().headers.get("Authorization")
             .and_then(|v| v.to_str().ok()).map(|s| s.to_string());
 
+        let format = ctx.response_format();
         if token.is_none() {
-            return Response::new(
-                StatusCode::UNAUTHORIZED,
-                Some(ErrorResponseBody {
-                    error: "Unauthorized".to_string(),
-                }.to_bytes()),
-            );
+            return WgpError::MissingToken.into_response_as(format);
         }
 
         let token = token.unwrap().replace(&"Bearer ".to_string(), &"".to_string());
@@ -143,22 +186,12 @@ impl WGPMessageHandler {
                 let username = claims.get_username();
                 debug!("username: {}", username);
                 if username.is_empty() {
-                    return Response::new(
-                        StatusCode::UNAUTHORIZED,
-                        Some(ErrorResponseBody {
-                            error: "Invalid token".to_string(),
-                        }.to_bytes()),
-                    );
+                    return WgpError::InvalidToken.into_response_as(format);
                 }
 
-                let db = self.get_db();
-                if !db.user_exists(&username) {
-                    return Response::new(
-                        StatusCode::UNAUTHORIZED,
-                        Some(ErrorResponseBody {
-                            error: "User does not exist".to_string(),
-                        }.to_bytes()),
-                    );
+                let lookup = username.clone();
+                if !self.with_db(move |db| db.user_exists(&lookup)).await {
+                    return WgpError::UnknownUser.into_response_as(format);
                 }
 
                 // set credentials in the context for further use
@@ -169,100 +202,43 @@ impl WGPMessageHandler {
             }
             Err(err) => {
                 error!("Validate token error {err:?}");
-                Response::new(
-                    StatusCode::UNAUTHORIZED,
-                    Some(ErrorResponseBody {
-                        error: "Invalid token".to_string(),
-                    }.to_bytes()),
-                )
+                WgpError::InvalidToken.into_response_as(format)
             }
         };
     }
 
-    pub fn get_profile(&self, ctx: &mut dyn ContextTrait) -> Response {
-        if let Some(username) = ctx.get("username") {
-            let db = self.get_db();
-            let metadata = db.get_metadata(username);
-
-            let response_body = GetProfileResponse {
-                metadata: metadata.unwrap().clone()
-            };
+    pub async fn get_profile(&self, AuthUser(username): AuthUser) -> Result<GetProfileResponse, WgpError> {
+        let lookup = username.clone();
+        let metadata = self.with_db(move |db| db.get_metadata(&lookup)).await
+            .ok_or_else(|| WgpError::NotFound(format!("Profile of {}", username)))?;
 
-            Response::new(StatusCode::OK, Some(response_body.to_bytes()))
-        } else {
-            error!("ERROR: Username not found in context");
-            Response::new(
-                StatusCode::INTERNAL_SERVER_ERROR,
-                None,
-            )
-        }
+        Ok(GetProfileResponse { metadata })
     }
 
-    pub fn get_poems(&self, ctx: &mut dyn ContextTrait) -> Response {
-        let db = self.get_db();
-        let id = ctx.param("id");
+    pub async fn get_poems(&self, QueryParams(params): QueryParams<ListParams>) -> Result<GetPoemsResponse, WgpError> {
+        Self::reject_id(&params, "/poems/{id}")?;
 
-        if let Some(id) = id {
-            if let Some(poem) = db.get_poem(&id) {
-                let response_body = GetPoemResponse {
-                    id: poem.id,
-                    title: poem.title.to_string(),
-                    author: poem.author.to_string(),
-                    content: poem.content.to_string(),
-                };
-
-                return Response {
-                    status: StatusCode::OK,
-                    body: Some(response_body.to_bytes()),
-                };
-            }
-            return Response {
-                status: StatusCode::NOT_FOUND,
-                body: Some(ErrorResponseBody {
-                    error: "Id not found".to_string(),
-                }.to_bytes()),
-            };
-        }
-
-
-        let response_body = GetPoemsResponse {
-            poems: Box::from(db.get_poems())
-        };
-
-        Response::new(StatusCode::OK, Some(response_body.to_bytes()))
+        Ok(GetPoemsResponse {
+            poems: Box::from(self.with_db(|db| db.get_poems()).await)
+        })
     }
 
-    pub fn get_images(&self, ctx: &mut dyn ContextTrait) -> Response {
-        let mut db = self.get_db();
-        let id = ctx.param("id");
+    pub async fn get_poem(&self, Params(IdParams { id }): Params<IdParams>) -> Result<GetPoemResponse, WgpError> {
+        let poem = self.with_db(move |db| db.get_poem(id)).await
+            .ok_or_else(|| WgpError::NotFound(format!("Poem with id {}", id)))?;
 
-        // If an id is provided, fetch the specific image
-        if let Some(id) = id {
-            return match db.get_image(&id) {
-                Ok(image) => {
-                    let response_body = GetImageResponse {
-                        id: image.id,
-                        title: image.name.clone(),
-                        file_name: image.file_name.clone(),
-                        content: image.content.clone(),
-                    };
+        Ok(GetPoemResponse {
+            id: poem.id,
+            title: poem.title.to_string(),
+            author: poem.author.to_string(),
+            content: poem.content.to_string(),
+        })
+    }
 
-                    Response::new(StatusCode::OK, Some(response_body.to_bytes()))
-                }
-                Err(err) => {
-                    error!("ERROR: {}", err);
-                    Response::new(
-                        StatusCode::NOT_FOUND,
-                        Some(ErrorResponseBody {
-                            error: err,
-                        }.to_bytes()),
-                    )
-                }
-            };
-        }
+    pub async fn get_images(&self, QueryParams(params): QueryParams<ListParams>) -> Result<GetImagesResponse, WgpError> {
+        Self::reject_id(&params, "/images/{id}")?;
 
-        // If no id is provided, return all images
-        let images = db.get_images();
+        let images = self.with_db(|db| db.get_images()).await;
         let img_response = images.into_iter().map(|img| GetImageResponse {
             id: img.id,
             title: img.name.clone(),
@@ -270,21 +246,46 @@ impl WGPMessageHandler {
             content: img.content.clone(),
         }).collect::<Vec<GetImageResponse>>();
 
-        let response_body = GetImagesResponse {
+        Ok(GetImagesResponse {
             images: Box::from(img_response)
-        };
-        Response::new(StatusCode::OK, Some(response_body.to_bytes()))
+        })
     }
 
-    pub fn ntor_init(&self, ctx: &mut dyn ContextTrait) -> Response {
-        let data = ctx.get_request_body();
-        let (body, error, status) = Self::parse_request_body::<NTorInitRequestBody>(data);
-        if status != StatusCode::OK {
-            return Response::new(status, error.map(|e| e.to_bytes()));
+    /// Lists used to be filtered with an `id` query parameter, which now selects a single item with `item_route`.
+    /// # Errors
+    /// * Returns WgpError::InvalidParameter if an `id` is given, rather than answering with the whole list.
+    fn reject_id(params: &ListParams, item_route: &str) -> Result<(), WgpError> {
+        match params.id {
+            Some(_) => Err(WgpError::InvalidParameter(format!("id is not a list filter, request {} instead", item_route))),
+            None => Ok(()),
         }
+    }
+
+    pub async fn get_image(&self, Params(IdParams { id }): Params<IdParams>) -> Result<GetImageResponse, WgpError> {
+        let mut image = self.with_db(move |db| db.get_image(id)).await
+            .ok_or_else(|| WgpError::NotFound(format!("Image with id {}", id)))?;
+
+        // the file is read without holding the storage
+        image.content = read_image_content(&image).await.map_err(WgpError::Internal)?;
+
+        Ok(GetImageResponse {
+            id: image.id,
+            title: image.name.clone(),
+            file_name: image.file_name.clone(),
+            content: image.content.clone(),
+        })
+    }
 
-        let request_body = body.unwrap(); // Unwrap the Option, safe because we checked status
+    /// Streams the raw content of an image from its file, so that large images are never held in memory at once.
+    pub async fn get_image_content(&self, Params(IdParams { id }): Params<IdParams>) -> Result<Response, WgpError> {
+        let image = self.with_db(move |db| db.get_image(id)).await
+            .ok_or_else(|| WgpError::NotFound(format!("Image with id {}", id)))?;
 
+        let stream = FileStream::open(image_path(&image)).await.map_err(WgpError::Internal)?;
+        Ok(Response::streamed(StatusCode::OK, Box::new(stream)))
+    }
+
+    pub async fn ntor_init(&self, Body(request_body): Body<NTorInitRequestBody>) -> Result<NTorInitResponse, WgpError> {
         // todo I think there are prettier ways to use nTor since we are free to modify the nTor crate, but I'm lazy
         let mut ntor_server = nTorServer::new_with_secret(
             self.config.ntor_server_id.clone(),
@@ -292,9 +293,13 @@ impl WGPMessageHandler {
         );
 
         if request_body.public_key.len() != 32 {
-            return Response::new(StatusCode::BAD_REQUEST, None);
+            return Err(WgpError::InvalidPublicKey);
         }
 
+        let (version, suite) = ntor_server.negotiate(&request_body.versions, &request_body.suites)
+            .ok_or(WgpError::UnsupportedProtocol)?;
+        debug!("nTor protocol version {} with {}", version, suite.name());
+
         // Client initializes session with the server
         let init_session_msg = InitSessionMessage::from(request_body.public_key);
 
@@ -307,101 +312,601 @@ impl WGPMessageHandler {
             t_hash: init_session_response.t_hash,
             session_id: ntor_session_id.clone(),
             static_public_key: ntor_server.get_certificate().public_key.to_bytes().to_vec(),
-            server_id: self.config.ntor_server_id.clone()
+            server_id: self.config.ntor_server_id.clone(),
+            version,
+            suite,
         };
 
-        // save nTor session
-        let mut db = self.get_db();
-        db.save_ntor_session(&ntor_session_id, ntor_server);
+        // save nTor session, making room for it first
+        let max_sessions = self.config.ntor_session.max_sessions;
+        let session = NTorSession::new(ntor_server, unix_timestamp());
+        self.with_db(move |db| {
+            match db.evict_ntor_sessions(max_sessions - 1) {
+                Ok(0) => {}
+                Ok(evicted) => debug!("evicted {} least recently used nTor sessions", evicted),
+                Err(err) => return Err(WgpError::Internal(format!("unable to evict nTor sessions: {}", err))),
+            }
+
+            db.save_ntor_session(&ntor_session_id, session)
+                .map_err(|err| WgpError::Internal(format!("unable to save nTor session: {}", err)))
+        }).await?;
 
-        Response::new(
-            StatusCode::OK,
-            Some(response.to_bytes()),
-        )
+        Ok(response)
     }
 
-    pub fn ntor_encrypt(&self, ctx: &mut dyn ContextTrait) -> Response {
-        let response_bytes = ctx.get_response_body();
-        let db = self.get_db();
+    /// Terminates the nTor session of the request, which must have been decrypted with it first.
+    pub async fn ntor_close(&self, ctx: &mut dyn ContextTrait) -> Response {
+        let format = ctx.response_format();
+        let Some(session_id) = Self::ntor_session_id(ctx.request_header()) else {
+            return WgpError::NoNTorSession.into_response_as(format);
+        };
 
-        // todo consider where to put ntor session id
-        let ntor_session_id = ctx.request_header().headers.get("nTor_session_id")
-            .and_then(|v| v.to_str().ok()).map(|s| s.to_string());
+        match self.with_db(move |db| db.remove_ntor_session(&session_id)).await {
+            Ok(true) => NTorCloseResponse { success: true }.into_response_as(format),
+            Ok(false) => WgpError::NoNTorSession.into_response_as(format),
+            Err(err) => WgpError::Internal(format!("unable to remove nTor session: {}", err)).into_response_as(format),
+        }
+    }
 
-        if let Some(session_id) = ntor_session_id {
-            debug!("Session id: {}", session_id);
-            let ntor_server = db.get_ntor_session(&session_id);
-            // debug!("Response bytes: {}", hex::encode(response_bytes.clone()));
-
-            if let Some(server) = ntor_server {
-                return match server.encrypt(response_bytes.clone()) {
-                    Ok((nonce, encrypted)) => {
-                        // let decrypted = server.decrypt(nonce.clone(), encrypted.clone()).unwrap();
-                        // println!("Decrypted bytes: {}", hex::encode(decrypted));
-                        Response::new(
-                            StatusCode::OK,
-                            Some(NTorEncryptMessage { nonce, encrypted }.to_bytes()),
-                        )
-                    }
-                    Err(err) => {
-                        error!("unable to encrypt: {}", err);
-                        Response::new(
-                            StatusCode::INTERNAL_SERVER_ERROR,
-                            None,
-                        )
-                    }
+    /// Encrypts the response of the rest of the route with the client's nTor session, whatever its status,
+    /// so that error bodies don't leak in plaintext either.
+    /// The NTorEncryptMessage is serialized in the format negotiated with the client, like the response it encrypts,
+    /// or framed in a binary [`Envelope`] if the client prefers `application/octet-stream`.
+    /// Streamed bodies are encrypted chunk by chunk into frames, see [`EncryptedFrames`] and [`FrameEncoding`].
+    pub async fn ntor_encrypt(&self, ctx: &mut dyn ContextTrait, next: Next<'_>) -> Response {
+        let mut response = next.run(ctx).await;
+
+        let format = ctx.response_format();
+        let (session_id, aad) = match Self::session_and_aad(ctx.request_header(), Direction::ServerToClient) {
+            Ok(session_and_aad) => session_and_aad,
+            Err(err) => return err.into_response_as(format),
+        };
+
+        // the headers of the plaintext response are kept, except its content type which now is the envelope's
+        let mut headers = std::mem::take(&mut response.headers);
+        headers.remove(CONTENT_TYPE);
+
+        if let Some(stream) = response.stream.take() {
+            let encoding = FrameEncoding::of_request(ctx.request_header(), format);
+            let frames = EncryptedFrames {
+                inner: stream,
+                db: self.db.clone(),
+                config: self.config.ntor_session.clone(),
+                session_id,
+                aad,
+                encoding,
+                index: 0,
+                done: false,
+            };
+            let mut response = Response::streamed(response.status, Box::new(frames));
+            response.headers = headers;
+            return response.with_header(CONTENT_TYPE, encoding.content_type()).vary_on_accept();
+        }
+
+        let response_bytes = response.body.unwrap_or_default();
+        let config = self.config.ntor_session.clone();
+        let lookup = session_id.clone();
+        let result = self.with_db(move |db| Self::encrypt_with_session(db, &config, &lookup, &aad, response_bytes)).await;
+
+        let binary = Envelope::accepted(ctx.request_header());
+        let encrypted = result.and_then(|message| if binary {
+            let envelope = Envelope { content_format: format, session_id, nonce: message.nonce, encrypted: message.encrypted };
+            Ok((envelope.to_bytes()?, HeaderValue::from_static(OCTET_STREAM)))
+        } else {
+            Ok((message.to_bytes_as(format), format.content_type()))
+        });
+
+        match encrypted {
+            Ok((encrypted, content_type)) => {
+                let mut response = Response::new(response.status, Some(encrypted));
+                response.headers = headers;
+                response.with_header(CONTENT_TYPE, content_type).vary_on_accept()
+            }
+            Err(err) => err.into_response_as(format),
+        }
+    }
+
+    /// Decrypts the request body of the rest of the route with the client's nTor session, see [`RequestDecrypter`]:
+    /// a buffered body right away, and a streamed one as the handlers read it, see `Router::add_streaming`.
+    pub async fn ntor_decrypt(&self, ctx: &mut dyn ContextTrait) -> Response {
+        let mut decrypter = match self.request_decrypter(ctx.request_header()) {
+            Ok(decrypter) => decrypter,
+            Err(err) => return err.into_response_as(ctx.response_format()),
+        };
+
+        if ctx.streams_body() {
+            // envelopes tell the format of their content once opened, too late for the handlers of a stream
+            if let Some(format) = decrypter.format {
+                ctx.set_request_format(format);
+            }
+            ctx.set_body_decoder(Box::new(decrypter));
+            return Response::new(StatusCode::OK, None);
+        }
+
+        match decrypter.decode(ctx.get_request_body().clone(), true).await {
+            Ok(decrypted) => {
+                ctx.set_request_body(decrypted);
+                if let Some(format) = decrypter.format {
+                    ctx.set_request_format(format);
                 }
+                Response::new(StatusCode::OK, None)
             }
+            Err(err) => err.into_response_as(ctx.response_format()),
         }
+    }
 
-        Response::new(
-            StatusCode::BAD_REQUEST,
-            Some(ErrorResponseBody { error: "no nTor session found".to_string() }.to_bytes()),
-        )
+    /// Returns a decrypter of the request body with the client's nTor session,
+    /// which tells a body sent in frames from a single message by its media type.
+    fn request_decrypter(&self, request_header: &RequestHeader) -> Result<RequestDecrypter, WgpError> {
+        let (session_id, aad) = Self::session_and_aad(request_header, Direction::ClientToServer)?;
+        let encoding = FrameEncoding::of_content_type(request_header);
+        let format = match encoding {
+            Some(FrameEncoding::Message(format)) => Some(format),
+            Some(FrameEncoding::Envelope(_)) => None,
+            None => Format::of_request(request_header).ok(),
+        };
+
+        Ok(RequestDecrypter {
+            db: self.db.clone(),
+            config: self.config.ntor_session.clone(),
+            session_id,
+            aad,
+            frames: encoding.map(FrameReader::new),
+            body: Vec::new(),
+            format,
+        })
+    }
+
+    fn ntor_session_id(request_header: &RequestHeader) -> Option<String> {
+        // todo consider where to put ntor session id
+        request_header.headers.get("nTor_session_id")
+            .and_then(|v| v.to_str().ok()).map(|s| s.to_string())
+    }
+
+    /// Binds encrypted bodies to the method and path of the request, and to the session.
+    fn ntor_associated_data(request_header: &RequestHeader, direction: Direction, session_id: &str) -> Vec<u8> {
+        associated_data(direction, request_header.method.as_str(), request_header.uri.path(), session_id)
     }
 
-    pub fn ntor_decrypt(&self, ctx: &mut dyn ContextTrait) -> Response {
-        let data = ctx.get_request_body();
-        let (body, error, status) = Self::parse_request_body::<NTorEncryptMessage>(data);
-        if status != StatusCode::OK {
-            return Response::new(status, error.map(|e| e.to_bytes()));
+    /// Returns the nTor session id named in the request header, and the associated data of a message in `direction`.
+    fn session_and_aad(request_header: &RequestHeader, direction: Direction) -> Result<(String, Vec<u8>), WgpError> {
+        let Some(session_id) = Self::ntor_session_id(request_header) else {
+            return Err(WgpError::NoNTorSession);
+        };
+
+        debug!("Session id: {}", session_id);
+        let aad = Self::ntor_associated_data(request_header, direction, &session_id);
+        Ok((session_id, aad))
+    }
+
+    /// Encrypts `data` into an NTorEncryptMessage with a session.
+    /// The storage must stay locked until the session is saved back, so that concurrent requests never reuse a sequence number.
+    fn encrypt_with_session(db: &mut dyn Storage, config: &NTorSessionConfig, session_id: &str, aad: &[u8], data: Vec<u8>) -> Result<NTorEncryptMessage, WgpError> {
+        let Some(mut session) = Self::load_session(db, config, session_id) else {
+            return Err(WgpError::NoNTorSession);
+        };
+
+        let (nonce, encrypted) = session.server.encrypt(data, aad)?;
+
+        Self::save_session(db, session_id, session)?;
+        Ok(NTorEncryptMessage { nonce, encrypted })
+    }
+
+    /// Encrypts a chunk of a streamed body for the frame `index`, to be written with a [`FrameEncoding`].
+    fn encrypt_frame_with_session(db: &mut dyn Storage, config: &NTorSessionConfig, session_id: &str, aad: &[u8], index: u64, last: bool, chunk: Vec<u8>) -> Result<NTorEncryptMessage, WgpError> {
+        let frame_aad = frame_associated_data(aad, index, last);
+        Self::encrypt_with_session(db, config, session_id, &frame_aad, chunk)
+    }
+
+    /// Decrypts an NTorEncryptMessage with a session, the associated data of frames binding their index, see [`RequestDecrypter`].
+    /// The storage must stay locked until the session is saved back, so that a message replayed concurrently is still detected.
+    fn decrypt_with_session(db: &mut dyn Storage, config: &NTorSessionConfig, session_id: &str, aad: &[u8], message: NTorEncryptMessage) -> Result<Vec<u8>, WgpError> {
+        let Some(mut session) = Self::load_session(db, config, session_id) else {
+            return Err(WgpError::NoNTorSession);
+        };
+
+        let decrypted = session.server.decrypt(message.nonce, message.encrypted, aad).inspect_err(|err| {
+            error!("unable to decrypt: {}", err);
+        })?;
+
+        Self::save_session(db, session_id, session)?;
+        Ok(decrypted)
+    }
+
+    /// Returns the session if it has not expired yet, expired sessions are removed right away
+    /// instead of waiting for the reaper.
+    fn load_session(db: &mut dyn Storage, config: &NTorSessionConfig, session_id: &str) -> Option<NTorSession> {
+        let session = db.get_ntor_session(session_id)?;
+        if !session.is_expired(config, unix_timestamp()) {
+            return Some(session);
         }
 
-        let request_body = body.unwrap(); // Unwrap the Option, safe because we checked status
+        debug!("nTor session {} expired", session_id);
+        if let Err(err) = db.remove_ntor_session(session_id) {
+            error!("unable to remove nTor session: {}", err);
+        }
+        None
+    }
 
-        // todo consider where to put ntor session id
-        let ntor_session_id = ctx.request_header().headers.get("nTor_session_id")
-            .and_then(|v| v.to_str().ok()).map(|s| s.to_string());
+    /// Saves the sequencing state of a session back after a message was encrypted or decrypted.
+    fn save_session(db: &mut dyn Storage, session_id: &str, mut session: NTorSession) -> Result<(), WgpError> {
+        session.last_used_at = unix_timestamp();
+        db.save_ntor_session(session_id, session)
+            .map_err(|err| WgpError::Internal(format!("unable to save nTor session: {}", err)))
+    }
+}
 
-        if let Some(session_id) = ntor_session_id {
-            debug!("Session id: {}", session_id);
-            let db = self.get_db();
-            let ntor_server = db.get_ntor_session(&session_id);
-
-            if let Some(server) = ntor_server {
-                return match server.decrypt(request_body.nonce, request_body.encrypted) {
-                    Ok(decrypted) => {
-                        ctx.set_request_body(decrypted);
-                        Response::new(
-                            StatusCode::OK,
-                            None,
-                        )
-                    }
-                    Err(err) => {
-                        error!("unable to decrypt: {}", err);
-                        Response::new(
-                            StatusCode::INTERNAL_SERVER_ERROR,
-                            None,
-                        )
-                    }
+impl UpstreamCipher for WGPMessageHandler {
+    // pingora's response body filter is synchronous, so the upstream cipher locks the storage on the worker thread,
+    // while request bodies are decrypted on the blocking thread pool like the handlers'
+    fn verify_request(&self, request_header: &RequestHeader) -> Result<(), WgpError> {
+        let session_id = Self::ntor_session_id(request_header);
+        match session_id {
+            Some(session_id) if Self::load_session(self.get_db().as_mut(), &self.config.ntor_session, &session_id).is_some() => Ok(()),
+            _ => Err(WgpError::NoNTorSession),
+        }
+    }
+
+    fn request_decoder(&self, request_header: &RequestHeader) -> Result<Box<dyn BodyDecoder>, WgpError> {
+        Ok(Box::new(self.request_decrypter(request_header)?))
+    }
+
+    fn encrypt_response_frame(&self, request_header: &RequestHeader, chunk: Vec<u8>, index: u64, last: bool) -> Result<Vec<u8>, WgpError> {
+        let (session_id, aad) = Self::session_and_aad(request_header, Direction::ServerToClient)?;
+        let message = Self::encrypt_frame_with_session(self.get_db().as_mut(), &self.config.ntor_session, &session_id, &aad, index, last, chunk)?;
+        FrameEncoding::of_upstream_request(request_header).encode(session_id, message)
+    }
+
+    fn response_content_type(&self, request_header: &RequestHeader) -> HeaderValue {
+        FrameEncoding::of_upstream_request(request_header).content_type()
+    }
+}
+
+/// Runs `f` with the locked storage on the blocking thread pool, see `WGPMessageHandler::with_db`.
+async fn run_with_db<R, F>(db: Arc<Mutex<Box<dyn Storage>>>, f: F) -> R
+where
+    F: FnOnce(&mut dyn Storage) -> R + Send + 'static,
+    R: Send + 'static,
+{
+    tokio::task::spawn_blocking(move || f(db.lock().unwrap().as_mut()))
+        .await
+        .expect("storage task panicked")
+}
+
+/// FrameEncoding tells how the frames of an encrypted stream are written: response frames follow the client's
+/// preference like the envelope of whole bodies, see [`Envelope::accepted`], and request frames are told by their media type,
+/// see [`FrameEncoding::of_content_type`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FrameEncoding {
+    /// NTorEncryptMessages serialized in the given format, like whole bodies: JSON ones each followed by a newline,
+    /// binary ones each prefixed with their u32 big-endian length as they may contain newlines
+    Message(Format),
+    /// binary envelopes of content in the given format, written back to back as they are self-delimiting,
+    /// the format of request frames being only known from the envelopes themselves
+    Envelope(Format),
+}
+
+impl FrameEncoding {
+    fn of_request(request_header: &RequestHeader, format: Format) -> FrameEncoding {
+        if Envelope::accepted(request_header) {
+            FrameEncoding::Envelope(format)
+        } else {
+            FrameEncoding::Message(format)
+        }
+    }
+
+    /// The content of upstream responses is opaque to the proxy, so their envelopes tell the negotiated format.
+    fn of_upstream_request(request_header: &RequestHeader) -> FrameEncoding {
+        FrameEncoding::of_request(request_header, Format::negotiate(request_header).unwrap_or_default())
+    }
+
+    /// Returns the encoding of a request body sent in frames, whose media type is the one of response frames,
+    /// see [`FrameEncoding::content_type`], or None if the body is a single message.
+    fn of_content_type(request_header: &RequestHeader) -> Option<FrameEncoding> {
+        let content_type = request_header.headers.get(CONTENT_TYPE)?.to_str().ok()?;
+        let mut parts = content_type.split(';');
+        let essence = parts.next().unwrap_or_default().trim().to_ascii_lowercase();
+        let framing = parts
+            .filter_map(|param| param.split_once('='))
+            .find(|(name, _)| name.trim().eq_ignore_ascii_case("framing"))
+            .map(|(_, value)| value.trim().trim_matches('"').to_ascii_lowercase());
+
+        match (essence.as_str(), framing.as_deref()) {
+            ("application/x-ndjson", _) => Some(FrameEncoding::Message(Format::Json)),
+            (OCTET_STREAM, Some("envelopes")) => Some(FrameEncoding::Envelope(Format::default())),
+            (_, Some("length-prefixed")) => Format::from_media_type(content_type)
+                .filter(|format| matches!(format, Format::Cbor | Format::MessagePack))
+                .map(FrameEncoding::Message),
+            _ => None,
+        }
+    }
+
+    fn content_type(self) -> HeaderValue {
+        match self {
+            FrameEncoding::Message(Format::Json | Format::JsonByteArrays) => HeaderValue::from_static("application/x-ndjson"),
+            FrameEncoding::Message(Format::Cbor) => HeaderValue::from_static("application/cbor; framing=length-prefixed"),
+            FrameEncoding::Message(Format::MessagePack) => HeaderValue::from_static("application/msgpack; framing=length-prefixed"),
+            FrameEncoding::Envelope(_) => HeaderValue::from_static("application/octet-stream; framing=envelopes"),
+        }
+    }
+
+    fn encode(self, session_id: String, message: NTorEncryptMessage) -> Result<Vec<u8>, WgpError> {
+        match self {
+            FrameEncoding::Message(format @ (Format::Json | Format::JsonByteArrays)) => {
+                let mut frame = message.to_bytes_as(format);
+                frame.push(b'\n');
+                Ok(frame)
+            }
+            FrameEncoding::Message(format) => {
+                let message = message.to_bytes_as(format);
+                let length = u32::try_from(message.len())
+                    .map_err(|_| WgpError::Internal("frame too long to be length-prefixed".to_string()))?;
+                let mut frame = length.to_be_bytes().to_vec();
+                frame.extend_from_slice(&message);
+                Ok(frame)
+            }
+            FrameEncoding::Envelope(content_format) => {
+                Envelope { content_format, session_id, nonce: message.nonce, encrypted: message.encrypted }.to_bytes()
+            }
+        }
+    }
+
+    /// Finds the frame `bytes` start with, returning its length and the range of the message it holds,
+    /// or None until the whole frame arrived.
+    fn find_frame(self, bytes: &[u8]) -> Result<Option<(usize, Range<usize>)>, WgpError> {
+        let frame = match self {
+            FrameEncoding::Message(Format::Json | Format::JsonByteArrays) => {
+                bytes.iter().position(|&byte| byte == b'\n').map(|end| (end + 1, 0..end))
+            }
+            FrameEncoding::Message(_) => bytes.get(..4).and_then(|prefix| {
+                let length = u32::from_be_bytes(prefix.try_into().unwrap()) as usize;
+                (bytes.len() >= 4 + length).then_some((4 + length, 4..4 + length))
+            }),
+            FrameEncoding::Envelope(_) => Envelope::length(bytes)?
+                .filter(|&length| bytes.len() >= length)
+                .map(|length| (length, 0..length)),
+        };
+        Ok(frame)
+    }
+
+    /// Parses a message written with [`FrameEncoding::encode`], returning it with the format of the plaintext it encrypts.
+    fn decode(self, session_id: &str, message: &[u8]) -> Result<(NTorEncryptMessage, Format), WgpError> {
+        match self {
+            FrameEncoding::Message(format) => {
+                let message = WGPMessageHandler::parse_request_body::<NTorEncryptMessage>(message, format)?;
+                Ok((*message, format))
+            }
+            FrameEncoding::Envelope(_) => {
+                let envelope = Envelope::from_bytes(message)?;
+                // the associated data binds the message to the session of the header anyway, this only fails earlier
+                if envelope.session_id != session_id {
+                    return Err(WgpError::InvalidBody("nTor envelope of another session".to_string()));
                 }
+                Ok((NTorEncryptMessage { nonce: envelope.nonce, encrypted: envelope.encrypted }, envelope.content_format))
+            }
+        }
+    }
+}
+
+/// FrameReader splits a request body sent in frames, see [`FrameEncoding`], into its frames as its chunks arrive.
+/// Only the end of the body tells which frame is the last one, so the latest frame is held back until another one follows.
+struct FrameReader {
+    encoding: FrameEncoding,
+    buffer: Vec<u8>,
+    held: Option<Vec<u8>>,
+    index: u64,
+}
+
+/// Frame is a message of a request body sent in frames, see [`FrameReader`].
+#[derive(Debug, PartialEq)]
+struct Frame {
+    index: u64,
+    last: bool,
+    message: Vec<u8>,
+}
+
+impl FrameReader {
+    fn new(encoding: FrameEncoding) -> Self {
+        FrameReader { encoding, buffer: Vec::new(), held: None, index: 0 }
+    }
+
+    /// Adds a chunk of the body, returning the frames known to be complete by now.
+    /// # Errors
+    /// * Returns WgpError::InvalidBody if a frame is malformed, or if the body ends in the middle of a frame or without any.
+    fn push(&mut self, chunk: &[u8], end_of_stream: bool) -> Result<Vec<Frame>, WgpError> {
+        self.buffer.extend_from_slice(chunk);
+
+        let mut frames = Vec::new();
+        let mut start = 0;
+        while let Some((length, message)) = self.encoding.find_frame(&self.buffer[start..])? {
+            let message = self.buffer[start + message.start..start + message.end].to_vec();
+            start += length;
+            if let Some(previous) = self.held.replace(message) {
+                frames.push(self.frame(previous, false));
+            }
+        }
+        self.buffer.drain(..start);
+
+        if end_of_stream {
+            if !self.buffer.is_empty() {
+                return Err(WgpError::InvalidBody("nTor frame cut short".to_string()));
+            }
+            let Some(last) = self.held.take() else {
+                return Err(WgpError::InvalidBody("no nTor frame".to_string()));
+            };
+            frames.push(self.frame(last, true));
+        }
+        Ok(frames)
+    }
+
+    fn frame(&mut self, message: Vec<u8>, last: bool) -> Frame {
+        let frame = Frame { index: self.index, last, message };
+        self.index += 1;
+        frame
+    }
+}
+
+/// RequestDecrypter decrypts a request body with the client's nTor session as its chunks arrive.
+/// A body sent in frames is decrypted frame by frame, each frame being bound to its index and to whether it is the last one
+/// like the frames of responses, so that frames cannot be reordered, dropped or cut short, see [`frame_associated_data`].
+/// Any other body is a single message, either a binary [`Envelope`] or an NTorEncryptMessage serialized in the format
+/// of the request's `Content-Type`, decrypted once complete.
+struct RequestDecrypter {
+    db: Arc<Mutex<Box<dyn Storage>>>,
+    config: NTorSessionConfig,
+    session_id: String,
+    aad: Vec<u8>,
+    // None for a single message, buffered in `body` until complete
+    frames: Option<FrameReader>,
+    body: Vec<u8>,
+    // the format of the plaintext, known from the Content-Type or once an envelope is opened
+    format: Option<Format>,
+}
+
+impl RequestDecrypter {
+    /// Parses the messages completed by a chunk of the body, each with the associated data it is bound to.
+    fn messages(&mut self, chunk: Vec<u8>, end_of_stream: bool) -> Result<Vec<(NTorEncryptMessage, Vec<u8>)>, WgpError> {
+        let Some(frames) = self.frames.as_mut() else {
+            self.body.extend_from_slice(&chunk);
+            if !end_of_stream {
+                return Ok(Vec::new());
             }
+
+            let encoding = if Envelope::matches(&self.body) {
+                FrameEncoding::Envelope(Format::default())
+            } else {
+                FrameEncoding::Message(self.format.ok_or(WgpError::UnsupportedMediaType)?)
+            };
+            let (message, format) = encoding.decode(&self.session_id, &self.body)?;
+            self.format = Some(format);
+            return Ok(vec![(message, self.aad.clone())]);
+        };
+
+        let encoding = frames.encoding;
+        let mut messages = Vec::new();
+        for frame in frames.push(&chunk, end_of_stream)? {
+            let (message, format) = encoding.decode(&self.session_id, &frame.message)?;
+            self.format = Some(format);
+            messages.push((message, frame_associated_data(&self.aad, frame.index, frame.last)));
+        }
+        Ok(messages)
+    }
+}
+
+#[async_trait]
+impl BodyDecoder for RequestDecrypter {
+    async fn decode(&mut self, chunk: Vec<u8>, end_of_stream: bool) -> Result<Vec<u8>, WgpError> {
+        let messages = self.messages(chunk, end_of_stream)?;
+        if messages.is_empty() {
+            return Ok(Vec::new());
         }
 
-        Response::new(
-            StatusCode::BAD_REQUEST,
-            Some(ErrorResponseBody { error: "no nTor session found".to_string() }.to_bytes()),
-        )
+        let (config, session_id) = (self.config.clone(), self.session_id.clone());
+        run_with_db(self.db.clone(), move |db| {
+            let mut decrypted = Vec::new();
+            for (message, aad) in messages {
+                decrypted.extend(WGPMessageHandler::decrypt_with_session(db, &config, &session_id, &aad, message)?);
+            }
+            Ok(decrypted)
+        }).await
     }
+}
 
-}
\ No newline at end of file
+/// EncryptedFrames encrypts a streamed body with an nTor session, each chunk into its own frame as soon as it is produced.
+/// The stream ends with a last, empty frame, whose absence tells the client that the body was cut short.
+struct EncryptedFrames {
+    inner: Box<dyn BodyStream>,
+    db: Arc<Mutex<Box<dyn Storage>>>,
+    config: NTorSessionConfig,
+    session_id: String,
+    aad: Vec<u8>,
+    encoding: FrameEncoding,
+    index: u64,
+    done: bool,
+}
+
+#[async_trait]
+impl BodyStream for EncryptedFrames {
+    async fn next_chunk(&mut self) -> Result<Option<Vec<u8>>, String> {
+        if self.done {
+            return Ok(None);
+        }
+
+        let (chunk, last) = match self.inner.next_chunk().await? {
+            Some(chunk) => (chunk, false),
+            None => (Vec::new(), true),
+        };
+
+        let (config, session_id, aad, index) = (self.config.clone(), self.session_id.clone(), self.aad.clone(), self.index);
+        let encoding = self.encoding;
+        let frame = run_with_db(self.db.clone(), move |db| {
+            let message = WGPMessageHandler::encrypt_frame_with_session(db, &config, &session_id, &aad, index, last, chunk)?;
+            encoding.encode(session_id, message)
+        }).await.map_err(|err| format!("unable to encrypt frame {}: {}", index, err))?;
+
+        self.index += 1;
+        self.done = last;
+        Ok(Some(frame))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(content_type: &str) -> RequestHeader {
+        let mut request = RequestHeader::build("POST", b"/upload", None).unwrap();
+        request.insert_header(CONTENT_TYPE, content_type).unwrap();
+        request
+    }
+
+    fn frame(index: u64, last: bool, message: &[u8]) -> Frame {
+        Frame { index, last, message: message.to_vec() }
+    }
+
+    #[test]
+    fn request_frames_are_told_by_their_media_type() {
+        let encoding = |content_type| FrameEncoding::of_content_type(&request(content_type));
+
+        assert_eq!(encoding("application/x-ndjson"), Some(FrameEncoding::Message(Format::Json)));
+        assert_eq!(encoding("application/cbor; framing=length-prefixed"), Some(FrameEncoding::Message(Format::Cbor)));
+        assert_eq!(encoding("application/msgpack;Framing=\"length-prefixed\""), Some(FrameEncoding::Message(Format::MessagePack)));
+        assert_eq!(encoding("application/octet-stream; framing=envelopes"), Some(FrameEncoding::Envelope(Format::Json)));
+        assert_eq!(encoding("application/json; framing=length-prefixed"), None);
+        assert_eq!(encoding("application/octet-stream"), None);
+        assert_eq!(encoding("application/cbor"), None);
+
+        for encoding in [FrameEncoding::Message(Format::Json), FrameEncoding::Message(Format::Cbor), FrameEncoding::Message(Format::MessagePack), FrameEncoding::Envelope(Format::Json)] {
+            let content_type = encoding.content_type();
+            assert_eq!(FrameEncoding::of_content_type(&request(content_type.to_str().unwrap())), Some(encoding));
+        }
+    }
+
+    #[test]
+    fn frames_are_split_across_chunks_and_the_last_one_is_told_by_the_end() {
+        let mut reader = FrameReader::new(FrameEncoding::Message(Format::Json));
+        assert_eq!(reader.push(b"{\"a\"", false).unwrap(), []);
+        assert_eq!(reader.push(b":1}\n{\"b\":2}\n{", false).unwrap(), [frame(0, false, b"{\"a\":1}")]);
+        assert_eq!(reader.push(b"}\n", true).unwrap(), [frame(1, false, b"{\"b\":2}"), frame(2, true, b"{}")]);
+
+        let mut reader = FrameReader::new(FrameEncoding::Message(Format::Cbor));
+        assert_eq!(reader.push(&[0, 0, 0, 2, b'a', b'\n', 0, 0], false).unwrap(), []);
+        assert_eq!(reader.push(&[0, 0], true).unwrap(), [frame(0, false, b"a\n"), frame(1, true, b"")]);
+    }
+
+    #[test]
+    fn bodies_ending_mid_frame_or_without_frames_are_refused() {
+        let invalid = |result: Result<Vec<Frame>, WgpError>, reason: &str| {
+            matches!(result, Err(WgpError::InvalidBody(found)) if found == reason)
+        };
+
+        let mut reader = FrameReader::new(FrameEncoding::Message(Format::Json));
+        assert!(invalid(reader.push(b"{}\n{", true), "nTor frame cut short"));
+        let mut reader = FrameReader::new(FrameEncoding::Message(Format::MessagePack));
+        assert!(invalid(reader.push(&[0, 0, 0, 3, 1], true), "nTor frame cut short"));
+        let mut reader = FrameReader::new(FrameEncoding::Message(Format::Json));
+        assert!(invalid(reader.push(b"", true), "no nTor frame"));
+        let mut reader = FrameReader::new(FrameEncoding::Envelope(Format::Json));
+        assert!(invalid(reader.push(b"{}\n", false), "not an nTor envelope"));
+    }
+}
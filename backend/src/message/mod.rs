@@ -1,5 +1,8 @@
 pub mod handler;
 mod utils;
-mod db;
+mod password;
+pub mod db;
+pub mod reaper;
 mod types;
 mod ntor;
+mod extract;
@@ -1,11 +1,25 @@
-use crate::message::ntor::common::{Certificate, generate_private_public_key_pair, InitSessionMessage, InitSessionResponse, PrivatePublicKeyPair};
+use crate::message::ntor::common::{AeadSuite, Certificate, generate_private_public_key_pair, InitSessionMessage, InitSessionResponse, PrivatePublicKeyPair, SessionKeys};
+use crate::message::ntor::replay::ReplayWindow;
+use crate::message::ntor::common;
+use crate::error::WgpError;
 use hmac::{Hmac, Mac};
+use log::warn;
 use sha2::{Digest, Sha256};
 use x25519_dalek::PublicKey;
 
 pub struct Client {
     ephemeral_key_pair: PrivatePublicKeyPair,
-    shared_secret: Option<Vec<u8>>,
+    session_keys: Option<SessionKeys>,
+    // versions and AEAD suites advertised in the init request
+    versions: Vec<u16>,
+    suites: Vec<String>,
+    // protocol the server picked for the session
+    protocol_version: u16,
+    suite: AeadSuite,
+    // sequence number of the last message sent
+    send_sequence: u64,
+    // sequence numbers of the messages received
+    replay_window: ReplayWindow,
 }
 
 impl Client {
@@ -17,10 +31,34 @@ impl Client {
                 private_key: None,
                 public_key: PublicKey::from(zero_bytes)
             },
-            shared_secret: None,
+            session_keys: None,
+            versions: Vec::new(),
+            suites: Vec::new(),
+            protocol_version: common::PROTOCOL_VERSION,
+            suite: AeadSuite::default(),
+            send_sequence: 0,
+            replay_window: ReplayWindow::default(),
         }
     }
 
+    pub fn get_session_keys(&self) -> Option<&SessionKeys> {
+        self.session_keys.as_ref()
+    }
+
+    /// Sets the versions and AEAD suites the client advertises in its init request, by order of preference.
+    /// Clients advertising nothing get version 1 with AES-256-GCM, see common::negotiate_protocol.
+    pub fn advertise(&mut self, versions: Vec<u16>, suites: Vec<String>) {
+        self.versions = versions;
+        self.suites = suites;
+    }
+
+    /// Sets the protocol the server picked in its init response, before the response is handled
+    /// as the handshake is bound to it, see common::protocol_binding.
+    pub fn set_protocol(&mut self, version: u16, suite: AeadSuite) {
+        self.protocol_version = version;
+        self.suite = suite;
+    }
+
     pub fn initialise_session(&mut self) -> InitSessionMessage {
         self.ephemeral_key_pair = generate_private_public_key_pair();
 
@@ -37,6 +75,12 @@ impl Client {
     ) -> bool {
         println!("Client:");
 
+        // the server may only pick a version the client advertised
+        if !self.versions.is_empty() && !self.versions.contains(&self.protocol_version) {
+            warn!("server picked unadvertised nTor protocol version {}", self.protocol_version);
+            return false;
+        }
+
         // Step 18: Compute the shared secret.
         let mut buffer: Vec<u8> = Vec::new();
 
@@ -65,7 +109,7 @@ impl Client {
 
         // Instantiate and run hashing function
         let mut hasher = Sha256::new();
-        hasher.update(buffer);
+        hasher.update(&buffer);
         let sha256_hash = hasher.finalize();
         let sha256_hash: &[u8; 32] = match sha256_hash.as_slice().try_into() {
             Ok(array_ref) => array_ref,
@@ -77,7 +121,8 @@ impl Client {
         let secret_key_prime = &sha256_hash[0..16];
         println!("[Debug] Client secret key prime: {:?}", secret_key_prime);
 
-        let secret_key = &sha256_hash[16..];
+        // the session keys are expanded from the full secret input, the same way the server does
+        let session_keys = SessionKeys::derive(&buffer);
 
         // Step 19: Compute HMAC (t_b in the paper)
 
@@ -90,14 +135,12 @@ impl Client {
 
         let mut hmac_hash = Hmac::<Sha256>::new_from_slice(&buffer).unwrap();
         hmac_hash.update(secret_key_prime);
+        hmac_hash.update(&common::protocol_binding(&self.versions, &self.suites, self.protocol_version, self.suite));
         let computed_t_hash = hmac_hash.finalize().into_bytes().to_vec();
 
         // assert that computed_t_b_hash equals t_hash generated by server
         if computed_t_hash == msg.t_hash {
-            self.shared_secret = Some(secret_key.to_vec());
-
-            println!("Shared secret:");
-            println!("{:?}\n", secret_key);
+            self.session_keys = Some(session_keys);
             true
         } else {
             println!("Failed to verify the shared secret: try again bro.");
@@ -105,4 +148,32 @@ impl Client {
         }
     }
 
+    /// Encrypts a message for the server with the client to server key.
+    /// `aad` is the associated data built with common::associated_data.
+    pub fn encrypt(&mut self, data: Vec<u8>, aad: &[u8]) -> Result<([u8; 12], Vec<u8>), WgpError> {
+        if let Some(keys) = &self.session_keys {
+            self.send_sequence += 1;
+            let nonce = common::sequence_nonce(self.send_sequence);
+            return common::encrypt(self.suite, keys.client_to_server_key.to_vec(), nonce, aad, data).map(|encrypted| (nonce, encrypted))
+        }
+        Err(WgpError::NoSessionKeys)
+    }
+
+    /// Decrypts a message from the server with the server to client key.
+    /// Messages replayed or too old for the replay window are rejected with WgpError::Replayed.
+    pub fn decrypt(&mut self, nonce: [u8; 12], data: Vec<u8>, aad: &[u8]) -> Result<Vec<u8>, WgpError> {
+        let Some(keys) = &self.session_keys else {
+            return Err(WgpError::NoSessionKeys);
+        };
+
+        let sequence = common::nonce_sequence(&nonce);
+        if !self.replay_window.check(sequence) {
+            return Err(WgpError::Replayed);
+        }
+
+        let decrypted = common::decrypt(self.suite, nonce, keys.server_to_client_key.to_vec(), aad, data)?;
+        self.replay_window.update(sequence);
+        Ok(decrypted)
+    }
+
 }
\ No newline at end of file
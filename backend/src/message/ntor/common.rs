@@ -1,10 +1,14 @@
-use log::{debug, error};
+use hkdf::Hkdf;
+use log::error;
 use rand_core::OsRng;
 use ring::aead;
-use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
 use x25519_dalek::{PublicKey, StaticSecret};
+use crate::error::WgpError;
 use crate::message::ntor::utils::vec_to_array32;
 
+#[derive(Clone)]
 pub struct PrivatePublicKeyPair {
     // In the future, type StaticSecret should be reserved for the server's static and the EphemeralSecret reserved for the ephemeral private key.
     // However, as a quirk of the nTOR protocol, we also need to use StaticSecret for the client's ephemeral private key hence why it is adopted here.
@@ -47,43 +51,385 @@ pub struct InitSessionResponse {
     pub t_hash: Vec<u8>
 }
 
-pub(crate) fn encrypt(key_bytes: Vec<u8>, mut data: Vec<u8>) -> Result<([u8; 12], Vec<u8>), &'static str> {
-    let key = aead::UnboundKey::new(&aead::AES_256_GCM, &key_bytes);
+/// Protocol identifier of the handshake, also labelling the key derivation.
+pub(crate) const PROTOID: &str = "ntor";
+
+/// Version of the session protocol: the handshake, the key derivation and the message sequencing below.
+/// It is bumped whenever one of them changes, the server keeping older versions until their clients are gone.
+pub const PROTOCOL_VERSION: u16 = 1;
+/// Protocol versions the server speaks.
+pub const SUPPORTED_VERSIONS: &[u16] = &[PROTOCOL_VERSION];
+
+/// AeadSuite is the AEAD protecting the messages of a session, negotiated by the handshake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum AeadSuite {
+    #[default]
+    #[serde(rename = "aes-256-gcm")]
+    Aes256Gcm,
+    #[serde(rename = "chacha20-poly1305")]
+    ChaCha20Poly1305,
+}
+
+impl AeadSuite {
+    /// Suites the server supports.
+    pub const SUPPORTED: &'static [AeadSuite] = &[AeadSuite::Aes256Gcm, AeadSuite::ChaCha20Poly1305];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            AeadSuite::Aes256Gcm => "aes-256-gcm",
+            AeadSuite::ChaCha20Poly1305 => "chacha20-poly1305",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<AeadSuite> {
+        AeadSuite::SUPPORTED.iter().copied().find(|suite| suite.name().eq_ignore_ascii_case(name))
+    }
+
+    fn algorithm(self) -> &'static aead::Algorithm {
+        match self {
+            AeadSuite::Aes256Gcm => &aead::AES_256_GCM,
+            AeadSuite::ChaCha20Poly1305 => &aead::CHACHA20_POLY1305,
+        }
+    }
+}
+
+/// Picks the protocol of a session from the versions and AEAD suites a client advertises:
+/// the highest version both sides speak, and the first suite of the client the server supports,
+/// so that clients lacking AES hardware can prefer ChaCha20-Poly1305. Unknown versions and suites are ignored.
+/// Clients advertising nothing predate the negotiation, and get version 1 with AES-256-GCM.
+/// Returns None if the client and the server have no version or no suite in common.
+/// The choice is bound to the handshake, see [`protocol_binding`].
+pub fn negotiate_protocol(versions: &[u16], suites: &[String]) -> Option<(u16, AeadSuite)> {
+    let version = if versions.is_empty() {
+        PROTOCOL_VERSION
+    } else {
+        versions.iter().copied().filter(|version| SUPPORTED_VERSIONS.contains(version)).max()?
+    };
+
+    let suite = if suites.is_empty() {
+        AeadSuite::default()
+    } else {
+        suites.iter().find_map(|suite| AeadSuite::from_name(suite))?
+    };
+
+    Some((version, suite))
+}
+
+/// Binds the negotiation of a session to its handshake: the versions and suites the client advertised,
+/// followed by the version and suite the server picked, are fed into the MAC `t_hash` after the secret,
+/// so that a client whose advertisement or whose server's pick was tampered with fails the handshake
+/// instead of settling for a downgraded protocol.
+/// It is `versions | suites | version | suite`, lists prefixed with their u16 big-endian count,
+/// versions as u16 big-endian and suite names prefixed with their u16 big-endian length.
+/// Clients advertising nothing predate the negotiation and bind nothing, leaving their handshake unchanged.
+pub fn protocol_binding(versions: &[u16], suites: &[String], version: u16, suite: AeadSuite) -> Vec<u8> {
+    if versions.is_empty() && suites.is_empty() {
+        return Vec::new();
+    }
+
+    let put_name = |binding: &mut Vec<u8>, name: &str| {
+        binding.extend_from_slice(&(name.len() as u16).to_be_bytes());
+        binding.extend_from_slice(name.as_bytes());
+    };
+
+    let mut binding = Vec::new();
+    binding.extend_from_slice(&(versions.len() as u16).to_be_bytes());
+    for advertised in versions {
+        binding.extend_from_slice(&advertised.to_be_bytes());
+    }
+    binding.extend_from_slice(&(suites.len() as u16).to_be_bytes());
+    for advertised in suites {
+        put_name(&mut binding, advertised);
+    }
+    binding.extend_from_slice(&version.to_be_bytes());
+    put_name(&mut binding, suite.name());
+    binding
+}
+
+/// Keys of an established session, derived with Tor's ntor KDF:
+/// `KEY_SEED = HMAC-SHA256(t_key, secret_input)` and `K = HKDF-SHA256-Expand(KEY_SEED, m_expand, 128)`,
+/// where `t_key = PROTOID | ":key_extract"` and `m_expand = PROTOID | ":key_expand"`.
+/// Like Tor's `Df | Db | Kf | Kb`, K is split into the forward (client to server) and backward
+/// (server to client) MAC keys, followed by the forward and backward encryption keys.
+#[derive(Clone)]
+pub struct SessionKeys {
+    pub client_to_server_mac: [u8; 32],
+    pub server_to_client_mac: [u8; 32],
+    pub client_to_server_key: [u8; 32],
+    pub server_to_client_key: [u8; 32],
+}
+
+impl SessionKeys {
+    /// `secret_input` is `EXP(X,y) | EXP(X,b) | ID | X | Y | PROTOID`, as computed by both sides of the handshake.
+    pub fn derive(secret_input: &[u8]) -> Self {
+        let t_key = format!("{PROTOID}:key_extract");
+        let m_expand = format!("{PROTOID}:key_expand");
+
+        let hkdf = Hkdf::<Sha256>::new(Some(t_key.as_bytes()), secret_input);
+        let mut okm = [0u8; 128];
+        hkdf.expand(m_expand.as_bytes(), &mut okm)
+            .expect("128 bytes is a valid HKDF-SHA256 output length");
+
+        Self::from_bytes(&okm).unwrap()
+    }
+
+    /// Serializes the keys in the order they are derived, e.g. to persist a session.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        [
+            self.client_to_server_mac,
+            self.server_to_client_mac,
+            self.client_to_server_key,
+            self.server_to_client_key,
+        ].concat()
+    }
+
+    /// Returns None if `bytes` is not exactly 128 bytes long.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != 128 {
+            return None;
+        }
+
+        let key = |index: usize| -> [u8; 32] { bytes[index * 32..(index + 1) * 32].try_into().unwrap() };
+        Some(SessionKeys {
+            client_to_server_mac: key(0),
+            server_to_client_mac: key(1),
+            client_to_server_key: key(2),
+            server_to_client_key: key(3),
+        })
+    }
+}
+
+/// Each side numbers the messages it sends from 1, and uses the number as the nonce:
+/// 4 zero bytes followed by the big-endian sequence number.
+/// Since both directions use distinct keys, a nonce is never reused with the same key.
+pub(crate) fn sequence_nonce(sequence: u64) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[4..].copy_from_slice(&sequence.to_be_bytes());
+    nonce
+}
+
+pub(crate) fn nonce_sequence(nonce: &[u8; 12]) -> u64 {
+    u64::from_be_bytes(nonce[4..].try_into().unwrap())
+}
+
+/// Direction a message travels in, so that a server message cannot be reflected back as a client message.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    ClientToServer,
+    ServerToClient,
+}
+
+/// Builds the AEAD associated data binding a message to the request it belongs to,
+/// so that an encrypted body cannot be moved to another endpoint, method or session undetected.
+/// It is `direction | method | path | session id`, each field prefixed with its u16 big-endian length.
+pub fn associated_data(direction: Direction, method: &str, path: &str, session_id: &str) -> Vec<u8> {
+    let direction = match direction {
+        Direction::ClientToServer => "client_to_server",
+        Direction::ServerToClient => "server_to_client",
+    };
+
+    let mut aad = Vec::new();
+    for field in [direction, method, path, session_id] {
+        aad.extend_from_slice(&(field.len() as u16).to_be_bytes());
+        aad.extend_from_slice(field.as_bytes());
+    }
+    aad
+}
+
+/// Extends the associated data of a streamed body for one of its frames, so that frames cannot be
+/// reordered, dropped or cut short undetected: it is `aad | index as u64 big-endian | 1 if last else 0`.
+pub fn frame_associated_data(aad: &[u8], index: u64, last: bool) -> Vec<u8> {
+    let mut frame_aad = aad.to_vec();
+    frame_aad.extend_from_slice(&index.to_be_bytes());
+    frame_aad.push(last as u8);
+    frame_aad
+}
+
+pub(crate) fn encrypt(suite: AeadSuite, key_bytes: Vec<u8>, nonce_bytes: [u8; 12], aad: &[u8], mut data: Vec<u8>) -> Result<Vec<u8>, WgpError> {
+    let key = aead::UnboundKey::new(suite.algorithm(), &key_bytes);
 
     if let Err(err) = key {
         error!("Error encrypt: {:?}", err);
-        return Err("encrypt failed")
+        return Err(WgpError::EncryptFailed)
     }
 
     let sealing_key = aead::LessSafeKey::new(key.unwrap());
 
-    let rng = SystemRandom::new();
-    let mut nonce_bytes = [0u8; 12];
-    rng.fill(&mut nonce_bytes).unwrap();
     let nonce = aead::Nonce::assume_unique_for_key(nonce_bytes);
 
-    return match sealing_key.seal_in_place_append_tag(nonce, aead::Aad::empty(), &mut data) {
+    return match sealing_key.seal_in_place_append_tag(nonce, aead::Aad::from(aad), &mut data) {
         Ok(()) => {
-            Ok((nonce_bytes, data))
+            Ok(data)
         }
         Err(err) => {
             error!("encrypt failed {:?}", err);
-            Err("encrypt failed")
+            Err(WgpError::EncryptFailed)
         }
     }
 }
 
-pub(crate) fn decrypt(nonce_bytes: [u8; 12], key_bytes: Vec<u8>, mut data: Vec<u8>) -> Result<Vec<u8>, &'static str> {
-    let key = aead::UnboundKey::new(&aead::AES_256_GCM, &key_bytes).unwrap();
+pub(crate) fn decrypt(suite: AeadSuite, nonce_bytes: [u8; 12], key_bytes: Vec<u8>, aad: &[u8], mut data: Vec<u8>) -> Result<Vec<u8>, WgpError> {
+    let key = aead::UnboundKey::new(suite.algorithm(), &key_bytes).map_err(|err| {
+        error!("Error decrypt: {:?}", err);
+        WgpError::DecryptFailed
+    })?;
     let opening_key = aead::LessSafeKey::new(key);
     let nonce = aead::Nonce::assume_unique_for_key(nonce_bytes);
 
-    let decrypted_data = opening_key.open_in_place(nonce, aead::Aad::empty(), &mut data).unwrap();
+    let decrypted_data = opening_key.open_in_place(nonce, aead::Aad::from(aad), &mut data).map_err(|err| {
+        error!("decrypt failed {:?}", err);
+        WgpError::DecryptFailed
+    })?;
 
-    debug!("Decrypted: {:?}", String::from_utf8_lossy(decrypted_data));
+    // the plaintext is never logged, as it holds credentials such as the passwords of /login and /register
     Ok(decrypted_data.to_vec())
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::ntor::client::Client;
+    use crate::message::ntor::server::Server;
+
+    fn keys_hex(keys: &SessionKeys) -> [String; 4] {
+        [
+            hex::encode(keys.client_to_server_mac),
+            hex::encode(keys.server_to_client_mac),
+            hex::encode(keys.client_to_server_key),
+            hex::encode(keys.server_to_client_key),
+        ]
+    }
 
+    #[test]
+    fn handshake_derives_the_same_keys_on_both_sides() {
+        let mut server = Server::new_with_secret("WGP Server".to_string(), [7u8; 32]);
+        let mut client = Client::new();
 
+        let init_msg = client.initialise_session();
+        let response = server.accept_init_session_request(&init_msg);
+        assert!(client.handle_response_from_server(&server.get_certificate(), &response));
+
+        let server_keys = server.get_session_keys().unwrap();
+        let client_keys = client.get_session_keys().unwrap();
+        assert_eq!(server_keys.to_bytes(), client_keys.to_bytes());
+        // both directions use distinct keys
+        assert_ne!(client_keys.client_to_server_key, client_keys.server_to_client_key);
+        assert_ne!(client_keys.client_to_server_mac, client_keys.server_to_client_mac);
+    }
+
+    #[test]
+    fn derive_matches_fixed_vectors() {
+        // a zeroed secret input of the size of a handshake with a 10 byte server id
+        let keys = SessionKeys::derive(&[0u8; 32 * 4 + 10 + 4]);
+        assert_eq!(keys_hex(&keys), [
+            "6de93df1ae3a990c91f9cdcd9987a6031f3ae46809f4a6235af55a0725f4a03d",
+            "b131b99b5fc492ac29762b44c2459b64e0e98ffbe23fc163118040277fd2625e",
+            "7f9d7a2037b2266f99f6c2a9ddbe71b413b8ffccb48d7c137b52a97a558336f4",
+            "26375e6593f8f39bee1b84bd3227957ffad3edbe03ee1812cf3c81dee3ae1fd4",
+        ]);
+
+        let secret_input: Vec<u8> = (0u8..=141).collect();
+        let keys = SessionKeys::derive(&secret_input);
+        assert_eq!(keys_hex(&keys), [
+            "268b38de39fb6022637e38b65ee73385a6f4cddec0a09f5087f43ca4426bb6b4",
+            "1305dbd607d7832bcef154b18725c47c36fc764220b903b303031cd5a1184caf",
+            "80ab5e6134d59ce100c2f3605f4a4b2d0c58e6d7a1277285d00810c70f6c60d8",
+            "775e80710ece56fb705307067f92d65647567e46e99fa099375acf8ce0e7a85e",
+        ]);
+        assert_eq!(SessionKeys::from_bytes(&keys.to_bytes()).map(|keys| keys_hex(&keys)), Some(keys_hex(&keys)));
+    }
+
+    fn names(suites: &[&str]) -> Vec<String> {
+        suites.iter().map(|suite| suite.to_string()).collect()
+    }
+
+    /// Runs a handshake where the client advertises `advertised`, while the server negotiates with what it
+    /// `received` and the client is `told` the server picked something else, as if tampered with on the way.
+    fn handshake(advertised: (&[u16], &[&str]), received: (&[u16], &[&str]), told: Option<(u16, AeadSuite)>) -> Option<(Server, Client)> {
+        let mut server = Server::new_with_secret("WGP Server".to_string(), [7u8; 32]);
+        let mut client = Client::new();
+        client.advertise(advertised.0.to_vec(), names(advertised.1));
+
+        let init_msg = client.initialise_session();
+        let picked = server.negotiate(received.0, &names(received.1))?;
+        let response = server.accept_init_session_request(&init_msg);
+        let (version, suite) = told.unwrap_or(picked);
+        client.set_protocol(version, suite);
+        client.handle_response_from_server(&server.get_certificate(), &response).then_some((server, client))
+    }
 
+    #[test]
+    fn negotiation_falls_back_to_version_1_with_aes_for_clients_advertising_nothing() {
+        assert_eq!(negotiate_protocol(&[], &[]), Some((1, AeadSuite::Aes256Gcm)));
+        assert_eq!(negotiate_protocol(&[1], &[]), Some((1, AeadSuite::Aes256Gcm)));
+        assert_eq!(negotiate_protocol(&[], &names(&["chacha20-poly1305"])), Some((1, AeadSuite::ChaCha20Poly1305)));
+    }
+
+    #[test]
+    fn negotiation_honours_the_client_suite_preference() {
+        assert_eq!(negotiate_protocol(&[1], &names(&["chacha20-poly1305", "aes-256-gcm"])), Some((1, AeadSuite::ChaCha20Poly1305)));
+        assert_eq!(negotiate_protocol(&[1], &names(&["aes-256-gcm", "chacha20-poly1305"])), Some((1, AeadSuite::Aes256Gcm)));
+        // unknown suites and versions are skipped, and names are case-insensitive
+        assert_eq!(negotiate_protocol(&[7, 1], &names(&["rot13", "ChaCha20-Poly1305"])), Some((1, AeadSuite::ChaCha20Poly1305)));
+    }
+
+    #[test]
+    fn negotiation_fails_without_a_common_version_or_suite() {
+        assert_eq!(negotiate_protocol(&[1], &names(&["rot13", "aes-128-gcm"])), None);
+        assert_eq!(negotiate_protocol(&[7], &names(&["aes-256-gcm"])), None);
+    }
+
+    #[test]
+    fn chacha20_poly1305_round_trips() {
+        let key = vec![3u8; 32];
+        let nonce = sequence_nonce(1);
+        let encrypted = encrypt(AeadSuite::ChaCha20Poly1305, key.clone(), nonce, b"aad", b"hello".to_vec()).unwrap();
+
+        assert_eq!(decrypt(AeadSuite::ChaCha20Poly1305, nonce, key.clone(), b"aad", encrypted.clone()).unwrap(), b"hello");
+        assert!(decrypt(AeadSuite::ChaCha20Poly1305, nonce, key.clone(), b"other", encrypted.clone()).is_err());
+        assert!(decrypt(AeadSuite::Aes256Gcm, nonce, key, b"aad", encrypted).is_err());
+    }
+
+    #[test]
+    fn keys_of_the_wrong_length_are_refused() {
+        let nonce = sequence_nonce(1);
+        assert!(matches!(encrypt(AeadSuite::Aes256Gcm, vec![3; 16], nonce, b"aad", b"hello".to_vec()), Err(WgpError::EncryptFailed)));
+        assert!(matches!(decrypt(AeadSuite::Aes256Gcm, nonce, vec![3; 16], b"aad", vec![0; 21]), Err(WgpError::DecryptFailed)));
+    }
+
+    #[test]
+    fn negotiated_sessions_use_the_picked_suite() {
+        let suites: &[&str] = &["chacha20-poly1305", "aes-256-gcm"];
+        let (mut server, mut client) = handshake((&[1], suites), (&[1], suites), None).unwrap();
+        assert_eq!((server.get_protocol_version(), server.get_suite()), (1, AeadSuite::ChaCha20Poly1305));
+
+        let (nonce, encrypted) = client.encrypt(b"ping".to_vec(), b"aad").unwrap();
+        assert_eq!(server.decrypt(nonce, encrypted, b"aad").unwrap(), b"ping");
+        let (nonce, encrypted) = server.encrypt(b"pong".to_vec(), b"aad").unwrap();
+        assert_eq!(client.decrypt(nonce, encrypted, b"aad").unwrap(), b"pong");
+    }
+
+    #[test]
+    fn handshakes_fail_when_the_negotiation_is_tampered_with() {
+        let suites: &[&str] = &["chacha20-poly1305", "aes-256-gcm"];
+
+        // the server's pick rewritten on its way to the client
+        assert!(handshake((&[1], suites), (&[1], suites), Some((1, AeadSuite::Aes256Gcm))).is_none());
+        // the client's preference reordered, or its advertisement stripped, on its way to the server
+        assert!(handshake((&[1], suites), (&[1], &["aes-256-gcm", "chacha20-poly1305"]), None).is_none());
+        assert!(handshake((&[1], suites), (&[], &[]), None).is_none());
+        // a version the client never advertised
+        assert!(handshake((&[1], suites), (&[1], suites), Some((2, AeadSuite::ChaCha20Poly1305))).is_none());
+        // clients advertising nothing keep the original handshake
+        assert!(handshake((&[], &[]), (&[], &[]), None).is_some());
+    }
+
+    #[test]
+    fn sessions_of_unsupported_versions_are_refused() {
+        let (mut server, _) = handshake((&[1], &["aes-256-gcm"]), (&[1], &["aes-256-gcm"]), None).unwrap();
+        server.set_protocol(2, AeadSuite::Aes256Gcm);
+
+        assert!(matches!(server.encrypt(b"ping".to_vec(), b"aad"), Err(WgpError::UnsupportedProtocol)));
+        assert!(matches!(server.decrypt(sequence_nonce(1), vec![0; 20], b"aad"), Err(WgpError::UnsupportedProtocol)));
+    }
+}
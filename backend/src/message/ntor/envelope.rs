@@ -0,0 +1,286 @@
+use http::header::CONTENT_TYPE;
+use pingora::http::RequestHeader;
+use crate::error::WgpError;
+use crate::format::{Format, OCTET_STREAM};
+
+const MAGIC: &[u8; 4] = b"WGPN";
+/// Version of the envelope layout, bumped whenever it changes.
+pub const VERSION: u8 = 1;
+const NONCE_LENGTH: usize = 12;
+const TAG_LENGTH: usize = 16;
+
+/// Envelope is the compact binary wire format of an nTor message, sent as `application/octet-stream`
+/// instead of a serialized NTorEncryptMessage, whose byte fields cost a base64 or array encoding:
+///
+/// | field          | size                                              |
+/// |----------------|---------------------------------------------------|
+/// | magic `WGPN`   | 4 bytes                                           |
+/// | version        | 1 byte                                            |
+/// | content format | 1 byte: 0 for JSON, 1 for CBOR, 2 for MessagePack, 3 for JSON with byte arrays |
+/// | session id     | 1 byte length, followed by the id                 |
+/// | nonce          | 12 bytes, the big-endian sequence number          |
+/// | ciphertext     | 4 bytes big-endian length, followed by the ciphertext |
+/// | tag            | 16 bytes                                          |
+///
+/// The content format tells what the plaintext is serialized in, which the envelope's media type cannot.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Envelope {
+    pub content_format: Format,
+    pub session_id: String,
+    pub nonce: [u8; NONCE_LENGTH],
+    /// the ciphertext followed by its authentication tag, as sealed by the AEAD
+    pub encrypted: Vec<u8>,
+}
+
+impl Envelope {
+    /// Tells binary envelopes from serialized NTorEncryptMessages, which never start with the magic.
+    pub fn matches(bytes: &[u8]) -> bool {
+        bytes.starts_with(MAGIC)
+    }
+
+    /// Tells whether the client asked for a binary envelope in response: when `application/octet-stream` is its
+    /// preferred media range, or when it sent one without an `Accept` header.
+    pub fn accepted(request_header: &RequestHeader) -> bool {
+        let is_octet_stream = |media_type: &str| {
+            media_type.split(';').next().unwrap_or_default().trim().eq_ignore_ascii_case(OCTET_STREAM)
+        };
+
+        match Format::accepted_media_ranges(request_header).first() {
+            Some(media_range) => is_octet_stream(media_range),
+            None => request_header.headers.get(CONTENT_TYPE)
+                .and_then(|content_type| content_type.to_str().ok())
+                .is_some_and(is_octet_stream),
+        }
+    }
+
+    /// # Errors
+    /// * Returns WgpError::Internal if the session id or the ciphertext is too long to be framed.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, WgpError> {
+        let session_id_length = u8::try_from(self.session_id.len())
+            .map_err(|_| WgpError::Internal("nTor session id too long for an envelope".to_string()))?;
+        let (ciphertext, tag) = self.encrypted.split_at(self.encrypted.len().saturating_sub(TAG_LENGTH));
+        let ciphertext_length = u32::try_from(ciphertext.len())
+            .map_err(|_| WgpError::Internal("ciphertext too long for an envelope".to_string()))?;
+
+        let mut bytes = Vec::with_capacity(MAGIC.len() + 3 + self.session_id.len() + NONCE_LENGTH + 4 + self.encrypted.len());
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(VERSION);
+        bytes.push(format_code(self.content_format));
+        bytes.push(session_id_length);
+        bytes.extend_from_slice(self.session_id.as_bytes());
+        bytes.extend_from_slice(&self.nonce);
+        bytes.extend_from_slice(&ciphertext_length.to_be_bytes());
+        bytes.extend_from_slice(ciphertext);
+        bytes.extend_from_slice(tag);
+        Ok(bytes)
+    }
+
+    /// Returns the length of the envelope `bytes` start with, or None until enough of it arrived to tell,
+    /// so that envelopes written back to back can be split apart.
+    /// # Errors
+    /// * Returns WgpError::InvalidBody if the bytes do not start with an envelope.
+    pub fn length(bytes: &[u8]) -> Result<Option<usize>, WgpError> {
+        if !MAGIC.starts_with(&bytes[..bytes.len().min(MAGIC.len())]) {
+            return Err(invalid("not an nTor envelope"));
+        }
+
+        let session_id_at = MAGIC.len() + 2;
+        let Some(&session_id_length) = bytes.get(session_id_at) else {
+            return Ok(None);
+        };
+        let ciphertext_length_at = session_id_at + 1 + session_id_length as usize + NONCE_LENGTH;
+        let Some(ciphertext_length) = bytes.get(ciphertext_length_at..ciphertext_length_at + 4) else {
+            return Ok(None);
+        };
+        let ciphertext_length = u32::from_be_bytes(ciphertext_length.try_into().unwrap()) as usize;
+        Ok(Some(ciphertext_length_at + 4 + ciphertext_length + TAG_LENGTH))
+    }
+
+    /// # Errors
+    /// * Returns WgpError::InvalidBody if the bytes are not an envelope of a supported version.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Envelope, WgpError> {
+        let mut reader = Reader { bytes };
+        if reader.take(MAGIC.len())? != MAGIC {
+            return Err(invalid("not an nTor envelope"));
+        }
+
+        let version = reader.take(1)?[0];
+        if version != VERSION {
+            return Err(invalid(&format!("unsupported nTor envelope version {}", version)));
+        }
+
+        let content_format = format_of_code(reader.take(1)?[0])
+            .ok_or_else(|| invalid("unknown nTor envelope content format"))?;
+        let session_id_length = reader.take(1)?[0] as usize;
+        let session_id = String::from_utf8(reader.take(session_id_length)?.to_vec())
+            .map_err(|_| invalid("nTor envelope session id is not UTF-8"))?;
+        let nonce = reader.take(NONCE_LENGTH)?.try_into().unwrap();
+        let ciphertext_length = u32::from_be_bytes(reader.take(4)?.try_into().unwrap()) as usize;
+        let mut encrypted = reader.take(ciphertext_length)?.to_vec();
+        encrypted.extend_from_slice(reader.take(TAG_LENGTH)?);
+
+        if !reader.bytes.is_empty() {
+            return Err(invalid("trailing bytes after the nTor envelope"));
+        }
+        Ok(Envelope { content_format, session_id, nonce, encrypted })
+    }
+}
+
+fn format_code(format: Format) -> u8 {
+    match format {
+        Format::Json => 0,
+        Format::Cbor => 1,
+        Format::MessagePack => 2,
+        Format::JsonByteArrays => 3,
+    }
+}
+
+fn format_of_code(code: u8) -> Option<Format> {
+    match code {
+        0 => Some(Format::Json),
+        1 => Some(Format::Cbor),
+        2 => Some(Format::MessagePack),
+        3 => Some(Format::JsonByteArrays),
+        _ => None,
+    }
+}
+
+fn invalid(reason: &str) -> WgpError {
+    WgpError::InvalidBody(reason.to_string())
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, length: usize) -> Result<&'a [u8], WgpError> {
+        if self.bytes.len() < length {
+            return Err(invalid("truncated nTor envelope"));
+        }
+
+        let (taken, rest) = self.bytes.split_at(length);
+        self.bytes = rest;
+        Ok(taken)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn envelope() -> Envelope {
+        Envelope {
+            content_format: Format::Cbor,
+            session_id: "session".to_string(),
+            nonce: [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 7],
+            encrypted: (0..40).collect(),
+        }
+    }
+
+    fn is_invalid(bytes: &[u8], reason: &str) -> bool {
+        matches!(Envelope::from_bytes(bytes), Err(WgpError::InvalidBody(found)) if found.contains(reason))
+    }
+
+    #[test]
+    fn round_trips() {
+        let bytes = envelope().to_bytes().unwrap();
+        assert!(Envelope::matches(&bytes));
+        assert_eq!(Envelope::from_bytes(&bytes).unwrap(), envelope());
+
+        for content_format in [Format::Json, Format::JsonByteArrays, Format::Cbor, Format::MessagePack] {
+            let envelope = Envelope { content_format, ..envelope() };
+            assert_eq!(Envelope::from_bytes(&envelope.to_bytes().unwrap()).unwrap(), envelope);
+        }
+
+        // an empty plaintext seals to the tag alone, and an empty session id is still framed
+        let envelope = Envelope { session_id: String::new(), encrypted: vec![9; TAG_LENGTH], ..envelope() };
+        assert_eq!(Envelope::from_bytes(&envelope.to_bytes().unwrap()).unwrap(), envelope);
+    }
+
+    #[test]
+    fn lays_out_the_fields_in_order() {
+        let bytes = envelope().to_bytes().unwrap();
+
+        assert_eq!(&bytes[..4], b"WGPN");
+        assert_eq!(bytes[4], VERSION);
+        assert_eq!(bytes[5], 1);
+        assert_eq!(bytes[6], 7);
+        assert_eq!(&bytes[7..14], b"session");
+        assert_eq!(&bytes[14..26], &envelope().nonce);
+        assert_eq!(&bytes[26..30], &24u32.to_be_bytes());
+        assert_eq!(&bytes[30..], &envelope().encrypted[..]);
+    }
+
+    #[test]
+    fn tells_its_length_once_the_header_arrived() {
+        let bytes = envelope().to_bytes().unwrap();
+        let header_length = 30;
+        for length in 0..header_length {
+            assert_eq!(Envelope::length(&bytes[..length]).unwrap(), None, "{} bytes", length);
+        }
+        assert_eq!(Envelope::length(&bytes[..header_length]).unwrap(), Some(bytes.len()));
+
+        let mut back_to_back = bytes.clone();
+        back_to_back.extend_from_slice(&bytes);
+        assert_eq!(Envelope::length(&back_to_back).unwrap(), Some(bytes.len()));
+        assert!(matches!(Envelope::length(b"WGPX"), Err(WgpError::InvalidBody(_))));
+        assert!(matches!(Envelope::length(b"{"), Err(WgpError::InvalidBody(_))));
+    }
+
+    #[test]
+    fn rejects_bad_magic() {
+        let mut bytes = envelope().to_bytes().unwrap();
+        bytes[0] = b'X';
+        assert!(!Envelope::matches(&bytes));
+        assert!(is_invalid(&bytes, "not an nTor envelope"));
+    }
+
+    #[test]
+    fn rejects_other_versions() {
+        let mut bytes = envelope().to_bytes().unwrap();
+        bytes[4] = VERSION + 1;
+        assert!(is_invalid(&bytes, "unsupported nTor envelope version"));
+    }
+
+    #[test]
+    fn rejects_unknown_content_formats() {
+        let mut bytes = envelope().to_bytes().unwrap();
+        bytes[5] = 4;
+        assert!(is_invalid(&bytes, "unknown nTor envelope content format"));
+    }
+
+    #[test]
+    fn rejects_truncated_envelopes() {
+        let bytes = envelope().to_bytes().unwrap();
+        for length in 0..bytes.len() {
+            assert!(Envelope::from_bytes(&bytes[..length]).is_err(), "accepted {} of {} bytes", length, bytes.len());
+        }
+        assert!(is_invalid(&bytes[..bytes.len() - 1], "truncated nTor envelope"));
+        // a session id length running past the end
+        assert!(is_invalid(&[b'W', b'G', b'P', b'N', VERSION, 0, 255, b'a'], "truncated nTor envelope"));
+    }
+
+    #[test]
+    fn rejects_trailing_bytes() {
+        let mut bytes = envelope().to_bytes().unwrap();
+        bytes.push(0);
+        assert!(is_invalid(&bytes, "trailing bytes after the nTor envelope"));
+    }
+
+    #[test]
+    fn rejects_session_ids_too_long_to_frame() {
+        let envelope = Envelope { session_id: "a".repeat(256), ..envelope() };
+        assert!(matches!(envelope.to_bytes(), Err(WgpError::Internal(_))));
+
+        let envelope = Envelope { session_id: "a".repeat(255), ..envelope };
+        assert_eq!(Envelope::from_bytes(&envelope.to_bytes().unwrap()).unwrap(), envelope);
+    }
+
+    #[test]
+    fn rejects_session_ids_that_are_not_utf8() {
+        let mut bytes = envelope().to_bytes().unwrap();
+        bytes[7] = 0xff;
+        assert!(is_invalid(&bytes, "not UTF-8"));
+    }
+}
@@ -1,4 +1,6 @@
 pub mod common;
 pub mod client;
 pub mod server;
+pub mod replay;
+pub mod envelope;
 mod utils;
@@ -0,0 +1,128 @@
+/// number of sequence numbers tracked behind the highest accepted one
+const WINDOW_SIZE: u64 = 64;
+
+/// ReplayWindow is a sliding window over the sequence numbers of received messages, as in IPsec (RFC 4303).
+/// Messages may arrive out of order within the window, but each sequence number is accepted at most once
+/// and anything older than the window is rejected.
+#[derive(Clone, Default, Debug)]
+pub struct ReplayWindow {
+    /// highest sequence number accepted so far, 0 if none since sequence numbers start at 1
+    highest: u64,
+    /// bit i is set when `highest - i` has been accepted
+    bitmap: u64,
+}
+
+impl ReplayWindow {
+    pub fn from_parts(highest: u64, bitmap: u64) -> Self {
+        ReplayWindow { highest, bitmap }
+    }
+
+    pub fn parts(&self) -> (u64, u64) {
+        (self.highest, self.bitmap)
+    }
+
+    /// Tells whether `sequence` may still be accepted, without recording it.
+    pub fn check(&self, sequence: u64) -> bool {
+        if sequence == 0 {
+            return false;
+        }
+        if sequence > self.highest {
+            return true;
+        }
+
+        let offset = self.highest - sequence;
+        offset < WINDOW_SIZE && self.bitmap & (1 << offset) == 0
+    }
+
+    /// Records `sequence` as accepted. Only call this once the message has been authenticated,
+    /// otherwise forged messages could move the window forward.
+    pub fn update(&mut self, sequence: u64) {
+        if sequence > self.highest {
+            let shift = sequence - self.highest;
+            self.bitmap = if shift < WINDOW_SIZE { self.bitmap << shift } else { 0 };
+            self.bitmap |= 1;
+            self.highest = sequence;
+        } else {
+            self.bitmap |= 1 << (self.highest - sequence);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Checks and records `sequence` the way sessions do, returning whether it was accepted.
+    fn accept(window: &mut ReplayWindow, sequence: u64) -> bool {
+        if !window.check(sequence) {
+            return false;
+        }
+        window.update(sequence);
+        true
+    }
+
+    #[test]
+    fn rejects_duplicates() {
+        let mut window = ReplayWindow::default();
+
+        assert!(accept(&mut window, 1));
+        assert!(!accept(&mut window, 1));
+        assert!(accept(&mut window, 2));
+        assert!(!accept(&mut window, 2));
+        assert!(!accept(&mut window, 1));
+        // sequence numbers start at 1
+        assert!(!accept(&mut window, 0));
+    }
+
+    #[test]
+    fn accepts_out_of_order_messages_within_the_window() {
+        let mut window = ReplayWindow::default();
+
+        assert!(accept(&mut window, 70));
+        assert!(accept(&mut window, 67));
+        assert!(accept(&mut window, 69));
+        assert!(!accept(&mut window, 67));
+        // the oldest sequence number still in the window
+        assert!(accept(&mut window, 70 - (WINDOW_SIZE - 1)));
+        assert_eq!(window.parts().0, 70);
+    }
+
+    #[test]
+    fn rejects_messages_older_than_the_window() {
+        let mut window = ReplayWindow::default();
+
+        assert!(accept(&mut window, 100));
+        assert!(!accept(&mut window, 100 - WINDOW_SIZE));
+        assert!(!accept(&mut window, 1));
+        assert!(accept(&mut window, 100 - WINDOW_SIZE + 1));
+    }
+
+    #[test]
+    fn large_forward_jumps_reset_the_window() {
+        let mut window = ReplayWindow::default();
+
+        assert!(accept(&mut window, 1));
+        assert!(accept(&mut window, 2));
+        assert!(accept(&mut window, 1_000));
+        assert_eq!(window.parts(), (1_000, 1));
+        // earlier messages fell out of the window, later ones within it are still accepted once
+        assert!(!accept(&mut window, 2));
+        assert!(accept(&mut window, 999));
+        assert!(!accept(&mut window, 999));
+        assert!(accept(&mut window, u64::MAX));
+        assert!(!accept(&mut window, u64::MAX));
+    }
+
+    #[test]
+    fn survives_a_round_trip_through_its_parts() {
+        let mut window = ReplayWindow::default();
+        accept(&mut window, 5);
+        accept(&mut window, 3);
+
+        let (highest, bitmap) = window.parts();
+        let mut restored = ReplayWindow::from_parts(highest, bitmap);
+        assert!(!accept(&mut restored, 5));
+        assert!(!accept(&mut restored, 3));
+        assert!(accept(&mut restored, 4));
+    }
+}
@@ -1,20 +1,34 @@
 use crate::message::ntor::common::{
+    AeadSuite,
     Certificate,
     generate_private_public_key_pair,
     InitSessionMessage,
     InitSessionResponse,
     PrivatePublicKeyPair,
+    SessionKeys,
 };
+use crate::message::ntor::replay::ReplayWindow;
 use hmac::{Hmac, Mac};
 use sha2::{Digest, Sha256};
 use x25519_dalek::{PublicKey, StaticSecret};
 use crate::message::ntor::common;
+use crate::error::WgpError;
 
+#[derive(Clone)]
 pub struct Server {
     static_key_pair: PrivatePublicKeyPair,
     ephemeral_key_pair: PrivatePublicKeyPair,
     server_id: String,
-    shared_secret: Option<Vec<u8>>,
+    session_keys: Option<SessionKeys>,
+    // protocol negotiated by the handshake
+    protocol_version: u16,
+    suite: AeadSuite,
+    // negotiation bound into the handshake, see common::protocol_binding
+    protocol_binding: Vec<u8>,
+    // sequence number of the last message sent
+    send_sequence: u64,
+    // sequence numbers of the messages received
+    replay_window: ReplayWindow,
 }
 
 impl Server {
@@ -26,7 +40,12 @@ impl Server {
                 public_key: PublicKey::from([0; 32]),
             },
             server_id,
-            shared_secret: None,
+            session_keys: None,
+            protocol_version: common::PROTOCOL_VERSION,
+            suite: AeadSuite::default(),
+            protocol_binding: Vec::new(),
+            send_sequence: 0,
+            replay_window: ReplayWindow::default(),
             static_key_pair: generate_private_public_key_pair(),
         }
     }
@@ -39,7 +58,12 @@ impl Server {
                 public_key: PublicKey::from([0; 32]),
             },
             server_id,
-            shared_secret: None,
+            session_keys: None,
+            protocol_version: common::PROTOCOL_VERSION,
+            suite: AeadSuite::default(),
+            protocol_binding: Vec::new(),
+            send_sequence: 0,
+            replay_window: ReplayWindow::default(),
             static_key_pair: PrivatePublicKeyPair {
                 private_key: Some(static_private_key.clone()),
                 public_key: PublicKey::from(&static_private_key),
@@ -47,6 +71,68 @@ impl Server {
         }
     }
 
+    /// Restores a server whose handshake has already completed, e.g. a session read back from storage.
+    pub fn from_session_keys(server_id: String, session_keys: SessionKeys) -> Self {
+        Self {
+            ephemeral_key_pair: PrivatePublicKeyPair {
+                private_key: None,
+                public_key: PublicKey::from([0; 32]),
+            },
+            server_id,
+            session_keys: Some(session_keys),
+            protocol_version: common::PROTOCOL_VERSION,
+            suite: AeadSuite::default(),
+            protocol_binding: Vec::new(),
+            send_sequence: 0,
+            replay_window: ReplayWindow::default(),
+            static_key_pair: PrivatePublicKeyPair {
+                private_key: None,
+                public_key: PublicKey::from([0; 32]),
+            },
+        }
+    }
+
+    pub fn get_session_keys(&self) -> Option<&SessionKeys> {
+        self.session_keys.as_ref()
+    }
+
+    /// Picks the protocol of the session from the versions and suites the client advertises,
+    /// and binds them to the handshake. See common::negotiate_protocol.
+    pub fn negotiate(&mut self, versions: &[u16], suites: &[String]) -> Option<(u16, AeadSuite)> {
+        let (version, suite) = common::negotiate_protocol(versions, suites)?;
+        self.set_protocol(version, suite);
+        self.protocol_binding = common::protocol_binding(versions, suites, version, suite);
+        Some((version, suite))
+    }
+
+    /// Sets the protocol the session was negotiated with, e.g. when it is read back from storage.
+    pub fn set_protocol(&mut self, version: u16, suite: AeadSuite) {
+        self.protocol_version = version;
+        self.suite = suite;
+    }
+
+    pub fn get_protocol_version(&self) -> u16 {
+        self.protocol_version
+    }
+
+    pub fn get_suite(&self) -> AeadSuite {
+        self.suite
+    }
+
+    pub fn get_send_sequence(&self) -> u64 {
+        self.send_sequence
+    }
+
+    pub fn get_replay_window(&self) -> &ReplayWindow {
+        &self.replay_window
+    }
+
+    /// Restores the message sequencing state of a session read back from storage.
+    pub fn restore_sequencing(&mut self, send_sequence: u64, replay_window: ReplayWindow) {
+        self.send_sequence = send_sequence;
+        self.replay_window = replay_window;
+    }
+
     pub fn get_certificate(&self) -> Certificate {
         // Upon implementation and deployment, it's the Service Provider that will create and then upload a certificate to the Layer8 Authentication Server. Likely, Layer8 will also provide the necessary functions to create one for the client.
         Certificate {
@@ -88,7 +174,7 @@ impl Server {
 
         // Instantiate sha256 hash function and compute
         let mut hasher = Sha256::new();
-        hasher.update(buffer);
+        hasher.update(&buffer);
         let sha256_hash = hasher.finalize();
         let sha256_hash: &[u8; 32] = match sha256_hash.as_slice().try_into() {
             Ok(array_ref) => array_ref,
@@ -98,7 +184,6 @@ impl Server {
         };
 
         let secret_key_prime = &sha256_hash[0..16];
-        let secret_key = &sha256_hash[16..];
         println!("[Debug] Server secret key prime: {:?}", secret_key_prime);
 
         // Step 12: Compute HMAC (t_b in the paper):
@@ -116,12 +201,11 @@ impl Server {
 
         let mut hmac_hash = Hmac::<Sha256>::new_from_slice(&hmac_key_buffer).unwrap();
         hmac_hash.update(secret_key_prime);
+        hmac_hash.update(&self.protocol_binding);
         let output_hash = hmac_hash.finalize().into_bytes().to_vec();
 
-        self.shared_secret = Some(secret_key.to_vec());
-
-        println!("Shared secret:");
-        println!("{:?}\n", secret_key);
+        // the session keys are expanded from the full secret input rather than taken from the hash above
+        self.session_keys = Some(SessionKeys::derive(&buffer));
 
         InitSessionResponse {
             server_ephemeral_public_key: self.ephemeral_key_pair.public_key,
@@ -129,22 +213,36 @@ impl Server {
         }
     }
 
-    pub fn encrypt(&self, data: Vec<u8>) -> Result<([u8; 12], Vec<u8>), &'static str> {
-        if let Some(key) = self.shared_secret.clone() {
-            let mut encrypt_key = key.clone(); // fixme use key derivation
-            encrypt_key.extend(key.clone());
-            println!("Shared key: {}", hex::encode(key.clone()));
-            return common::encrypt(encrypt_key, data)
+    /// Encrypts a message for the client with the server to client key.
+    /// `aad` is the associated data built with common::associated_data.
+    pub fn encrypt(&mut self, data: Vec<u8>, aad: &[u8]) -> Result<([u8; 12], Vec<u8>), WgpError> {
+        let key = self.session_keys()?.server_to_client_key.to_vec();
+        self.send_sequence += 1;
+        let nonce = common::sequence_nonce(self.send_sequence);
+        common::encrypt(self.suite, key, nonce, aad, data).map(|encrypted| (nonce, encrypted))
+    }
+
+    /// Decrypts a message from the client with the client to server key.
+    /// Messages replayed or too old for the replay window are rejected with WgpError::Replayed.
+    pub fn decrypt(&mut self, nonce: [u8; 12], data: Vec<u8>, aad: &[u8]) -> Result<Vec<u8>, WgpError> {
+        let keys = self.session_keys()?;
+
+        let sequence = common::nonce_sequence(&nonce);
+        if !self.replay_window.check(sequence) {
+            return Err(WgpError::Replayed);
         }
-        Err("no encryption key found")
+
+        let decrypted = common::decrypt(self.suite, nonce, keys.client_to_server_key.to_vec(), aad, data)?;
+        self.replay_window.update(sequence);
+        Ok(decrypted)
     }
 
-    pub fn decrypt(&self, nonce: [u8; 12], data: Vec<u8>) -> Result<Vec<u8>, &'static str> {
-        if let Some(key) = self.shared_secret.clone() {
-            let mut decrypt_key = key.clone(); // fixme use key derivation
-            decrypt_key.extend(key.clone());
-            return common::decrypt(nonce, decrypt_key, data);
+    /// Returns the keys of the session, provided that its protocol version is still supported:
+    /// sessions of a version the server dropped, e.g. read back from storage, are refused rather than misread.
+    fn session_keys(&self) -> Result<&SessionKeys, WgpError> {
+        if !common::SUPPORTED_VERSIONS.contains(&self.protocol_version) {
+            return Err(WgpError::UnsupportedProtocol);
         }
-        Err("no decryption key found")
+        self.session_keys.as_ref().ok_or(WgpError::NoSessionKeys)
     }
 }
\ No newline at end of file
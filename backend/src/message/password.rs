@@ -0,0 +1,72 @@
+use argon2::{Algorithm, Argon2, Params, PasswordHash, PasswordHasher, PasswordVerifier, Version};
+use argon2::password_hash::SaltString;
+use log::error;
+use rand_core::OsRng;
+use crate::config::PasswordHashConfig;
+
+/// Argon2Hasher hashes passwords with argon2id and a random per-user salt.
+/// Hashes are stored as PHC strings, so they carry the salt and the cost parameters they were created with.
+pub struct Argon2Hasher {
+    argon2: Argon2<'static>,
+    // verified against when the user does not exist, so that unknown usernames take as long as wrong passwords
+    dummy_hash: String,
+}
+
+impl Argon2Hasher {
+    /// # Panics
+    /// * If the cost parameters are out of the ranges accepted by argon2.
+    pub fn new(config: &PasswordHashConfig) -> Self {
+        let params = Params::new(config.memory_kib, config.iterations, config.parallelism, None)
+            .expect("Invalid password hash parameters");
+        let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, params);
+
+        let mut hasher = Argon2Hasher { argon2, dummy_hash: String::new() };
+        hasher.dummy_hash = hasher.hash("dummy password").expect("Failed to hash dummy password");
+        hasher
+    }
+
+    pub fn hash(&self, password: &str) -> Result<String, String> {
+        let salt = SaltString::generate(&mut OsRng);
+        self.argon2.hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+            .map_err(|err| err.to_string())
+    }
+
+    /// Verifies `password` against a stored hash, comparing in constant time.
+    /// The hash is checked with the parameters it was created with, not the configured ones.
+    pub fn verify(&self, password: &str, hash: &str) -> bool {
+        match PasswordHash::new(hash) {
+            Ok(parsed) => self.argon2.verify_password(password.as_bytes(), &parsed).is_ok(),
+            Err(err) => {
+                error!("invalid password hash: {}", err);
+                false
+            }
+        }
+    }
+
+    /// Burns the same time as a failed verify, for logins with an unknown username.
+    pub fn verify_dummy(&self, password: &str) {
+        self.verify(password, &self.dummy_hash);
+    }
+
+    /// Tells whether a stored hash was created with another algorithm or other cost parameters than the configured ones.
+    pub fn needs_rehash(&self, hash: &str) -> bool {
+        let Ok(parsed) = PasswordHash::new(hash) else {
+            return true;
+        };
+
+        if parsed.algorithm != Algorithm::Argon2id.ident() {
+            return true;
+        }
+
+        match Params::try_from(&parsed) {
+            Ok(params) => {
+                let current = self.argon2.params();
+                params.m_cost() != current.m_cost()
+                    || params.t_cost() != current.t_cost()
+                    || params.p_cost() != current.p_cost()
+            }
+            Err(_) => true,
+        }
+    }
+}
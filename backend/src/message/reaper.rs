@@ -0,0 +1,44 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use async_trait::async_trait;
+use log::{debug, error};
+use pingora::server::ShutdownWatch;
+use pingora::services::background::BackgroundService;
+use crate::config::NTorSessionConfig;
+use crate::message::db::{expiry_cutoffs, Storage};
+use crate::message::utils::unix_timestamp;
+
+/// SessionReaper periodically removes the nTor sessions that have been idle or alive for too long.
+/// Expired sessions are also refused as soon as they are looked up, the reaper only reclaims their storage.
+pub struct SessionReaper {
+    db: Arc<Mutex<Box<dyn Storage>>>,
+    config: NTorSessionConfig,
+}
+
+impl SessionReaper {
+    pub fn new(db: Arc<Mutex<Box<dyn Storage>>>, config: NTorSessionConfig) -> Self {
+        SessionReaper { db, config }
+    }
+
+    fn reap(&self) {
+        let (idle_before, created_before) = expiry_cutoffs(&self.config, unix_timestamp());
+        match self.db.lock().unwrap().remove_expired_ntor_sessions(idle_before, created_before) {
+            Ok(0) => {}
+            Ok(removed) => debug!("removed {} expired nTor sessions", removed),
+            Err(err) => error!("unable to remove expired nTor sessions: {}", err),
+        }
+    }
+}
+
+#[async_trait]
+impl BackgroundService for SessionReaper {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        let mut interval = tokio::time::interval(Duration::from_secs(self.config.reap_interval_secs));
+        loop {
+            tokio::select! {
+                _ = shutdown.changed() => break,
+                _ = interval.tick() => self.reap(),
+            }
+        }
+    }
+}
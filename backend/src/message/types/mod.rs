@@ -1,5 +1,10 @@
 use std::fmt::Debug;
+use http::header::CONTENT_TYPE;
+use pingora::http::StatusCode;
 use serde::{Deserialize, Serialize};
+use crate::format::Format;
+use crate::router::types::{IntoResponse, Response};
+use crate::validate::Validator;
 
 pub(crate) mod request;
 pub(crate) mod response;
@@ -7,19 +12,45 @@ pub(crate) mod other;
 
 pub trait ResponseBodyTrait: Serialize + for<'de> Deserialize<'de> + Debug {
     fn to_bytes(&self) -> Vec<u8> {
-        serde_json::to_vec(self).unwrap()
+        self.to_bytes_as(Format::Json)
+    }
+
+    fn to_bytes_as(&self, format: Format) -> Vec<u8> {
+        format.serialize(self).unwrap()
+    }
+}
+
+/// Response bodies are answered with 200, so that typed handlers can return them directly.
+impl<T: ResponseBodyTrait> IntoResponse for T {
+    fn into_response(self) -> Response {
+        self.into_response_as(Format::Json)
+    }
+
+    fn into_response_as(self, format: Format) -> Response {
+        Response::new(StatusCode::OK, Some(self.to_bytes_as(format)))
+            .with_header(CONTENT_TYPE, format.content_type())
+            .vary_on_accept()
     }
 }
 
 pub trait RequestBodyTrait: Serialize + for<'de> Deserialize<'de> + Debug {
-    fn from_bytes(bytes: Vec<u8>) -> Result<Box<Self>, serde_json::Error> {
-        serde_json::from_slice(&bytes)
+    fn from_bytes(bytes: Vec<u8>) -> Result<Box<Self>, String> {
+        Self::from_bytes_as(&bytes, Format::Json)
     }
+
+    fn from_bytes_as(bytes: &[u8], format: Format) -> Result<Box<Self>, String> {
+        format.deserialize(bytes)
+    }
+
+    /// Declares the validation rules of the body, which typed handlers only receive once they pass, see [`Validator`].
+    fn validate(&self, _validator: &mut Validator) {}
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct NTorEncryptMessage {
+    #[serde(with = "crate::format::base64_bytes")]
     pub nonce: [u8; 12],
+    #[serde(with = "crate::format::base64_bytes")]
     pub encrypted: Vec<u8>
 }
 
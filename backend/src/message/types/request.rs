@@ -1,23 +1,69 @@
 use std::fmt::Debug;
 use serde::{Deserialize, Serialize};
 use crate::message::types::RequestBodyTrait;
+use crate::validate::Validator;
 
-#[derive(Serialize, Deserialize, Debug)]
+const MAX_USERNAME_LENGTH: usize = 32;
+/// Passwords longer than this are refused before being hashed, as hashing them would only waste time.
+const MAX_PASSWORD_LENGTH: usize = 128;
+
+// missing fields default to empty, so that they are reported by the validation rules with the other invalid fields
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
 pub struct LoginRequestBody {
     pub username: String,
-    pub password: String, // todo change to hash
+    pub password: String,
+}
+impl RequestBodyTrait for LoginRequestBody {
+    // usernames are not checked further, so that users registered before the rules existed can still log in
+    fn validate(&self, validator: &mut Validator) {
+        validator.field("username", &self.username).required();
+        validator.field("password", &self.password).required().length(1, MAX_PASSWORD_LENGTH);
+    }
 }
-impl RequestBodyTrait for LoginRequestBody {}
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
 pub struct RegisterRequestBody {
     pub username: String,
-    pub password: String, // todo change to hash
+    pub password: String,
+}
+impl RequestBodyTrait for RegisterRequestBody {
+    fn validate(&self, validator: &mut Validator) {
+        validator.field("username", &self.username)
+            .required()
+            .length(3, MAX_USERNAME_LENGTH)
+            .charset(is_username_char, "letters, digits, '.', '-' and '_'");
+        validator.field("password", &self.password).required().length(8, MAX_PASSWORD_LENGTH);
+    }
+}
+
+fn is_username_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_')
 }
-impl RequestBodyTrait for RegisterRequestBody {}
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct NTorInitRequestBody {
-    pub public_key: Vec<u8>
+    #[serde(with = "crate::format::base64_bytes")]
+    pub public_key: Vec<u8>,
+    /// protocol versions the client speaks, empty for clients predating the negotiation
+    #[serde(default)]
+    pub versions: Vec<u16>,
+    /// AEAD suites the client supports by order of preference, e.g. `["chacha20-poly1305", "aes-256-gcm"]`
+    #[serde(default)]
+    pub suites: Vec<String>,
+}
+impl RequestBodyTrait for NTorInitRequestBody {}
+
+/// Path parameters of routes such as `/poems/{id}`.
+#[derive(Deserialize, Debug)]
+pub struct IdParams {
+    pub id: i32,
+}
+
+/// Query parameters of lists such as `/poems`. An `id` is refused rather than ignored,
+/// items being requested with routes such as `/poems/{id}`.
+#[derive(Deserialize, Debug)]
+pub struct ListParams {
+    pub id: Option<String>,
 }
-impl RequestBodyTrait for NTorInitRequestBody {}
\ No newline at end of file
@@ -1,15 +1,9 @@
 use std::fmt::Debug;
 use serde::{Deserialize, Serialize};
+use crate::message::ntor::common::AeadSuite;
 use crate::message::types::other::{Poem, UserMetadata};
 use crate::message::types::ResponseBodyTrait;
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct ErrorResponseBody {
-    pub error: String,
-}
-
-impl ResponseBodyTrait for ErrorResponseBody {}
-
 #[derive(Serialize, Deserialize, Debug)]
 pub struct LoginResponseBody {
     pub token: String,
@@ -54,6 +48,7 @@ pub struct GetImageResponse {
     pub id: i32,
     pub title: String,
     pub file_name: String,
+    #[serde(with = "crate::format::base64_bytes")]
     pub content: Vec<u8>,
 }
 
@@ -68,11 +63,24 @@ impl ResponseBodyTrait for GetImagesResponse {}
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct NTorInitResponse {
+    #[serde(with = "crate::format::base64_bytes")]
     pub public_key: Vec<u8>,
+    #[serde(with = "crate::format::base64_bytes")]
     pub t_hash: Vec<u8>,
     pub session_id: String,
+    #[serde(with = "crate::format::base64_bytes")]
     pub static_public_key: Vec<u8>, // fixme this field can be removed
-    pub server_id: String
+    pub server_id: String,
+    /// protocol version and AEAD suite the server picked for the session
+    pub version: u16,
+    pub suite: AeadSuite,
 }
 
 impl ResponseBodyTrait for NTorInitResponse {}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NTorCloseResponse {
+    pub success: bool,
+}
+
+impl ResponseBodyTrait for NTorCloseResponse {}
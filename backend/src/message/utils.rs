@@ -40,6 +40,10 @@ pub fn verify_jwt_token(token: &str, jwt_secret: [u8; 32]) -> Result<TokenData<C
     )
 }
 
+pub fn unix_timestamp() -> i64 {
+    chrono::Utc::now().timestamp()
+}
+
 pub fn new_nTor_session_id() -> String {
     Uuid::new_v4().to_string()
 }
@@ -0,0 +1,231 @@
+use http::header::{ACCESS_CONTROL_ALLOW_CREDENTIALS, ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_MAX_AGE, ACCESS_CONTROL_REQUEST_HEADERS, ACCESS_CONTROL_REQUEST_METHOD, ORIGIN, VARY};
+use http::{HeaderMap, HeaderValue};
+use pingora::http::{Method, RequestHeader};
+use crate::config::CorsConfig;
+
+/// Cors applies the configured cross-origin policy: it tells which CORS headers a response carries,
+/// and whether a preflight request is allowed.
+pub(crate) struct Cors {
+    any_origin: bool,
+    // lowercase exact origins and patterns, where `*` matches any sequence of characters
+    origins: Vec<String>,
+    methods: Vec<Method>,
+    any_header: bool,
+    // lowercase header names
+    headers: Vec<String>,
+    allow_credentials: bool,
+    max_age_secs: u64,
+}
+
+impl Cors {
+    pub(crate) fn from_config(config: &CorsConfig) -> Self {
+        let methods = config.allowed_methods.iter()
+            .map(|method| Method::from_bytes(method.as_bytes()).expect("Invalid CORS method"))
+            .collect();
+
+        Cors {
+            any_origin: config.allowed_origins.iter().any(|origin| origin == "*"),
+            origins: config.allowed_origins.iter().map(|origin| origin.to_ascii_lowercase()).collect(),
+            methods,
+            any_header: config.allowed_headers.iter().any(|header| header == "*"),
+            headers: config.allowed_headers.iter().map(|header| header.to_ascii_lowercase()).collect(),
+            allow_credentials: config.allow_credentials,
+            max_age_secs: config.max_age_secs,
+        }
+    }
+
+    /// Tells whether the request is a CORS preflight, rather than a plain OPTIONS request.
+    pub(crate) fn is_preflight(request_header: &RequestHeader) -> bool {
+        request_header.method == Method::OPTIONS
+            && request_header.headers.contains_key(ORIGIN)
+            && request_header.headers.contains_key(ACCESS_CONTROL_REQUEST_METHOD)
+    }
+
+    /// Returns the CORS headers of a response to the request, none if its origin is not allowed.
+    pub(crate) fn response_headers(&self, request_header: &RequestHeader) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        if !self.any_origin {
+            // the response depends on the origin, so caches must not serve it to other origins
+            headers.insert(VARY, HeaderValue::from_static("Origin"));
+        }
+
+        let Some(origin) = request_header.headers.get(ORIGIN) else {
+            return headers;
+        };
+
+        if self.any_origin {
+            headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, HeaderValue::from_static("*"));
+        } else if origin.to_str().is_ok_and(|origin| self.allows_origin(origin)) {
+            headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, origin.clone());
+        } else {
+            return headers;
+        }
+
+        if self.allow_credentials {
+            headers.insert(ACCESS_CONTROL_ALLOW_CREDENTIALS, HeaderValue::from_static("true"));
+        }
+        headers
+    }
+
+    /// Returns the headers of the response to a preflight request for a route allowing `route_methods`,
+    /// or None if its origin, requested method or one of its requested headers is not allowed.
+    /// The allowed methods are the route's, restricted to the configured ones if any.
+    pub(crate) fn preflight_headers(&self, request_header: &RequestHeader, route_methods: &[Method]) -> Option<HeaderMap> {
+        let mut headers = self.response_headers(request_header);
+        if !headers.contains_key(ACCESS_CONTROL_ALLOW_ORIGIN) {
+            return None;
+        }
+
+        // the configured methods restrict the route's, they never add methods the route does not serve
+        let methods: Vec<Method> = route_methods.iter()
+            .filter(|method| self.methods.is_empty() || self.methods.contains(method))
+            .cloned()
+            .collect();
+        let requested_method = request_header.headers.get(ACCESS_CONTROL_REQUEST_METHOD)
+            .and_then(|method| Method::from_bytes(method.as_bytes()).ok())?;
+        if !methods.contains(&requested_method) {
+            return None;
+        }
+
+        let requested_headers: Vec<String> = request_header.headers.get(ACCESS_CONTROL_REQUEST_HEADERS)
+            .and_then(|requested| requested.to_str().ok())
+            .map(|requested| requested.split(',')
+                .map(|header| header.trim().to_ascii_lowercase())
+                .filter(|header| !header.is_empty())
+                .collect())
+            .unwrap_or_default();
+        if !self.any_header && !requested_headers.iter().all(|header| self.headers.contains(header)) {
+            return None;
+        }
+
+        // a literal "*" is not honoured for credentialed requests, so the requested headers are echoed instead
+        let allowed_headers = if self.any_header { requested_headers } else { self.headers.clone() };
+
+        let allowed_methods = methods.iter().map(Method::as_str).collect::<Vec<_>>().join(", ");
+        headers.insert(ACCESS_CONTROL_ALLOW_METHODS, HeaderValue::from_str(&allowed_methods).ok()?);
+        if !allowed_headers.is_empty() {
+            headers.insert(ACCESS_CONTROL_ALLOW_HEADERS, HeaderValue::from_str(&allowed_headers.join(", ")).ok()?);
+        }
+        headers.insert(ACCESS_CONTROL_MAX_AGE, HeaderValue::from(self.max_age_secs));
+        Some(headers)
+    }
+
+    fn allows_origin(&self, origin: &str) -> bool {
+        let origin = origin.to_ascii_lowercase();
+        self.origins.iter().any(|pattern| wildcard_match(pattern, &origin))
+    }
+}
+
+/// Matches `value` against `pattern`, where `*` matches any sequence of characters, e.g. `https://*.example.com`.
+fn wildcard_match(pattern: &str, value: &str) -> bool {
+    let mut parts = pattern.split('*');
+    // split always yields at least one part
+    let first = parts.next().unwrap();
+    let Some(mut rest) = value.strip_prefix(first) else {
+        return false;
+    };
+
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        // no wildcard, the pattern is an exact origin
+        return rest.is_empty();
+    };
+
+    for part in middle {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cors(origins: &[&str], methods: &[&str], allow_credentials: bool) -> Cors {
+        Cors::from_config(&CorsConfig {
+            allowed_origins: origins.iter().map(|origin| origin.to_string()).collect(),
+            allowed_methods: methods.iter().map(|method| method.to_string()).collect(),
+            allow_credentials,
+            ..CorsConfig::default()
+        })
+    }
+
+    fn preflight(origin: &str, method: &str) -> RequestHeader {
+        let mut request = RequestHeader::build(Method::OPTIONS, b"/poems", None).unwrap();
+        request.insert_header("Origin", origin.to_string()).unwrap();
+        request.insert_header("Access-Control-Request-Method", method.to_string()).unwrap();
+        request
+    }
+
+    fn allowed_methods(headers: &HeaderMap) -> Option<&str> {
+        headers.get(ACCESS_CONTROL_ALLOW_METHODS).and_then(|methods| methods.to_str().ok())
+    }
+
+    #[test]
+    fn wildcard_match_exact_origins() {
+        assert!(wildcard_match("https://app.example.com", "https://app.example.com"));
+        assert!(!wildcard_match("https://app.example.com", "https://app.example.com.evil.net"));
+        assert!(!wildcard_match("https://app.example.com", "https://app.example.co"));
+    }
+
+    #[test]
+    fn wildcard_match_subdomain_patterns() {
+        let pattern = "https://*.example.com";
+        assert!(wildcard_match(pattern, "https://app.example.com"));
+        assert!(wildcard_match(pattern, "https://a.b.example.com"));
+        assert!(!wildcard_match(pattern, "https://example.com"));
+        assert!(!wildcard_match(pattern, "http://app.example.com"));
+        assert!(!wildcard_match(pattern, "https://app.example.com.evil.net"));
+        assert!(!wildcard_match(pattern, "https://evil.net/.example.com.x"));
+        assert!(wildcard_match("*", "https://anything.net"));
+    }
+
+    #[test]
+    fn origins_match_case_insensitively() {
+        let cors = cors(&["https://*.Example.com"], &[], false);
+        assert!(cors.allows_origin("HTTPS://APP.EXAMPLE.COM"));
+        assert!(cors.allows_origin("https://app.example.com"));
+        assert!(!cors.allows_origin("https://app.example.org"));
+    }
+
+    #[test]
+    fn preflight_methods_are_the_routes_restricted_to_the_configured_ones() {
+        let route_methods = [Method::GET, Method::HEAD, Method::OPTIONS];
+
+        let any = cors(&["https://app.example.com"], &[], false);
+        let headers = any.preflight_headers(&preflight("https://app.example.com", "GET"), &route_methods).unwrap();
+        assert_eq!(allowed_methods(&headers), Some("GET, HEAD, OPTIONS"));
+
+        let restricted = cors(&["https://app.example.com"], &["GET", "POST"], false);
+        let headers = restricted.preflight_headers(&preflight("https://app.example.com", "GET"), &route_methods).unwrap();
+        assert_eq!(allowed_methods(&headers), Some("GET"));
+        // configured, but not served by the route
+        assert!(restricted.preflight_headers(&preflight("https://app.example.com", "POST"), &route_methods).is_none());
+        // served by the route, but not configured
+        assert!(restricted.preflight_headers(&preflight("https://app.example.com", "HEAD"), &route_methods).is_none());
+        assert!(restricted.preflight_headers(&preflight("https://evil.net", "GET"), &route_methods).is_none());
+    }
+
+    #[test]
+    fn credentials_echo_the_origin() {
+        let cors = cors(&["https://*.example.com"], &[], true);
+        let headers = cors.response_headers(&preflight("https://app.example.com", "GET"));
+
+        assert_eq!(headers.get(ACCESS_CONTROL_ALLOW_ORIGIN).unwrap(), "https://app.example.com");
+        assert_eq!(headers.get(ACCESS_CONTROL_ALLOW_CREDENTIALS).unwrap(), "true");
+        assert_eq!(headers.get(VARY).unwrap(), "Origin");
+    }
+
+    #[test]
+    #[should_panic(expected = "allow_credentials")]
+    fn credentials_are_rejected_with_any_origin() {
+        CorsConfig {
+            allowed_origins: vec!["https://app.example.com".to_string(), "*".to_string()],
+            allow_credentials: true,
+            ..CorsConfig::default()
+        }.validate();
+    }
+}
@@ -1,102 +1,118 @@
 use std::collections::HashMap;
+use bytes::Bytes;
 use log::error;
+use http::header::{ALLOW, CONTENT_LENGTH, CONTENT_TYPE, TRANSFER_ENCODING, VARY};
+use http::{HeaderMap, HeaderValue};
 use pingora::http::{Method, ResponseHeader, StatusCode};
+use pingora::{Error, ErrorType};
 use pingora::prelude::Session;
-use regex::Regex;
+use crate::error::WgpError;
+use crate::format::{Format, OCTET_STREAM};
+use crate::proxy::cors::Cors;
 use crate::router::Router;
-use crate::router::types::{Response, Context, RequestSummary};
+use crate::router::query::Query;
+use crate::router::types::{Response, Context, IntoResponse, RequestSummary, RouteMode};
 
-pub(crate) struct ProxyHandler<T> {
-    router: Router<T>,
+pub(crate) struct ProxyHandler {
+    router: Router,
+    max_body_bytes: usize,
+    cors: Cors,
 }
 
-impl<T> ProxyHandler<T> {
-    pub(crate) fn new(router: Router<T>) -> Self {
-        ProxyHandler { router }
+impl ProxyHandler {
+    pub(crate) fn new(router: Router, max_body_bytes: usize, cors: Cors) -> Self {
+        ProxyHandler { router, max_body_bytes, cors }
     }
 
-    /// Extracts the request method and path from the session.
+    /// Extracts the request method, path and query parameters from the request URI.
     /// # Arguments
-    /// * `session` - A reference to the session object containing the request summary.
+    /// * `session` - A reference to the session object containing the request header.
     /// # Returns
-    /// * A tuple containing the HTTP method and the path as strings.
-    /// # Errors
-    /// * Returns an error if the method is unsupported or if the request summary is invalid (if it doesn't contain at least two parts (method and path)).
-    fn extract_request_summary(session: &Session) -> Result<RequestSummary, String> {
-        let request_summary = session.request_summary();
-        let parts: Vec<&str> = request_summary.split_whitespace().collect();
-
-        if parts.len() > 1 {
-            let method = match parts[0].to_string().as_str() {
-                "POST" => Method::POST,
-                "GET" => Method::GET,
-                "PUT" => Method::PUT,
-                "DELETE" => Method::DELETE,
-                "OPTIONS" => Method::OPTIONS,
-                _ => {
-                    error!("Unsupported method: {}", parts[0].to_string());
-                    return Err("Unsupported method")?;
-                }
-            };
-
-            let path = parts[1]
-                .trim_end_matches(',')
-                // .split('/')
-                // .collect::<Vec<&str>>()
-                // .get(1) // todo
-                // .unwrap_or(&"")
-                .to_string();
-
-            let base_path = path.split('?').next().unwrap_or(&path).to_string();
-
-            let re = Regex::new(r"\?([^#]*)").unwrap();
-            let mut queries = HashMap::new();
-
-            if let Some(caps) = re.captures(path.as_str()) {
-                let query = &caps[1];
-                for pair in query.split('&') {
-                    let mut iter = pair.splitn(2, '=');
-                    let key = iter.next().unwrap_or("");
-                    let value = iter.next().unwrap_or("");
-                    queries.insert(key.to_string(), value.to_string());
-                }
-            }
+    /// * The RequestSummary of the request, whose params hold the first value of each query parameter.
+    ///   Path parameters are only known once the router matches the route.
+    fn extract_request_summary(session: &Session) -> RequestSummary {
+        let request_header = session.req_header();
+        // the fragment is never sent by clients, and the URI drops it anyway
+        let query = Query::parse(request_header.uri.query().unwrap_or_default());
 
-            Ok(RequestSummary{ method, path: base_path, params: queries })
-        } else {
-            Err("Invalid request summary".to_string())
+        let mut params = HashMap::new();
+        for (key, value) in query.pairs() {
+            params.entry(key.clone()).or_insert_with(|| value.clone());
+        }
+
+        RequestSummary {
+            // any method is accepted, the router tells whether the path supports it
+            method: request_header.method.clone(),
+            path: request_header.uri.path().to_string(),
+            params,
+            path_params: HashMap::new(),
+            query,
         }
     }
 
     /// Validates the request by checking if the method and path are supported.
     /// # Arguments
     /// * `session` - A reference to the session object containing the request summary.
-    /// # Returns
-    /// * A StatusCode indicating the result of the validation.
-    pub(crate) fn validate_request(&self, session: &Session) -> StatusCode {
-        let request_summary = match ProxyHandler::<T>::extract_request_summary(session) {
-            Ok(request_summary) => request_summary,
-            Err(err) => {
-                error!("ERROR: {err}");
-                return StatusCode::METHOD_NOT_ALLOWED;
-            }
-        };
-
+    /// # Errors
+    /// * Returns WgpError::NotFound if the path is not routed, and WgpError::MethodNotAllowed if it is only under other methods.
+    pub(crate) fn validate_request(&self, session: &Session) -> Result<(), WgpError> {
+        let request_summary = ProxyHandler::extract_request_summary(session);
         if self.router.contains(&request_summary.method, &request_summary.path) {
-            StatusCode::OK
+            Ok(())
+        } else if self.router.allowed_methods(&request_summary.path).is_empty() {
+            Err(WgpError::NotFound("route".to_string()))
         } else {
-            StatusCode::NOT_FOUND
+            Err(WgpError::MethodNotAllowed)
+        }
+    }
+
+    /// Builds the Allow header value of 405 responses from the methods registered for the request's path.
+    /// # Arguments
+    /// * `session` - A reference to the session object containing the request summary.
+    /// # Returns
+    /// * The comma-separated list of allowed methods, or None if the path is not routed.
+    pub(crate) fn allow_header(&self, session: &Session) -> Option<String> {
+        let request_summary = ProxyHandler::extract_request_summary(session);
+        let methods = self.router.allowed_methods(&request_summary.path);
+        if methods.is_empty() {
+            return None;
         }
+
+        Some(methods.iter().map(Method::as_str).collect::<Vec<_>>().join(", "))
+    }
+
+    /// Tells whether the request is handled locally or forwarded to the upstream.
+    /// # Arguments
+    /// * `session` - A reference to the session object containing the request summary.
+    /// # Returns
+    /// * The RouteMode of the matched route, or None if the request is not routed.
+    pub(crate) fn route_mode(&self, session: &Session) -> Option<RouteMode> {
+        let request_summary = ProxyHandler::extract_request_summary(session);
+        self.router.route_mode(&request_summary.method, &request_summary.path)
+    }
+
+    /// Tells whether the request announces a body larger than the maximum body size with its Content-Length,
+    /// so that it is refused before being read. Chunked bodies are checked while they are read.
+    pub(crate) fn exceeds_body_limit(&self, session: &Session) -> bool {
+        session.req_header().headers.get("Content-Length")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<usize>().ok())
+            .is_some_and(|length| length > self.max_body_bytes)
+    }
+
+    pub(crate) fn max_body_bytes(&self) -> usize {
+        self.max_body_bytes
     }
 
     /// Reads the request body from the session.
     /// # Arguments
     /// * `session` - A mutable reference to the session object.
+    /// * `max_body_bytes` - The size the body may not exceed.
     /// # Returns
-    /// * A Result containing the request body as a Vec<u8> or an error.
+    /// * A Result containing the request body as a Vec<u8>.
     /// # Errors
-    /// * Returns an error if reading the request body fails.
-    async fn get_request_body(session: &mut Session) -> pingora::Result<Vec<u8>> {
+    /// * Returns WgpError::PayloadTooLarge if the body is too large, and WgpError::InvalidBody if reading it fails.
+    async fn get_request_body(session: &mut Session, max_body_bytes: usize) -> Result<Vec<u8>, WgpError> {
         // read request body
         let mut body = Vec::new();
         loop {
@@ -107,45 +123,166 @@ impl<T> ProxyHandler<T> {
                         None => break,
                     }
                 }
-                Err(err) => {
-                    error!("ERROR: {err}");
-                    return Err(err);
-                }
+                Err(err) => return Err(WgpError::InvalidBody(err.to_string())),
+            }
+
+            if body.len() > max_body_bytes {
+                return Err(WgpError::PayloadTooLarge);
             }
         }
         Ok(body)
     }
 
     /// Handles the request by extracting the method and path, and calling the appropriate handler.
+    /// The body is buffered beforehand, unless the route reads it itself, see `Router::add_streaming`.
     /// # Arguments
     /// * `session` - A mutable reference to the session object.
     /// # Returns
-    /// * A tuple containing an optional response body as Vec<u8> and a StatusCode.
+    /// * The Response of the handlers.
     pub(crate) async fn handle_request(&self, session: &mut Session) -> Response {
-        // read request body
-        match ProxyHandler::<T>::get_request_body(session).await {
-            Ok(request_body) => {
-                // request_validation is called before this function, so we can assume that the request is valid
-                let req_summary = ProxyHandler::<T>::extract_request_summary(session).unwrap();
-                let mut context = Context::new(req_summary, request_body, session); // todo rethink logic of creating context because method, path and body can be extracted from session
+        let req_summary = ProxyHandler::extract_request_summary(session);
 
-                self.router.call_handler(&mut context)
-            }
-            Err(err) => {
-                error!("ERROR: {err}");
-                Response::new(StatusCode::INTERNAL_SERVER_ERROR, None)
-            }
+        if self.router.streams_body(&req_summary.method, &req_summary.path) {
+            let mut context = Context::streaming(req_summary, session, self.max_body_bytes);
+            return self.router.call_handler(&mut context).await;
         }
+
+        let request_body = match ProxyHandler::get_request_body(session, self.max_body_bytes).await {
+            Ok(request_body) => request_body,
+            Err(err) => return err.into_response_as(Format::negotiate(session.req_header()).unwrap_or_default()),
+        };
+
+        let mut context = Context::new(req_summary, request_body, session);
+        self.router.call_handler(&mut context).await
     }
 
-    pub(crate) async fn set_headers(response_status: StatusCode, body_bytes: &Vec<u8>, session: &mut Session) -> pingora::Result<()> {
+    /// Answers CORS preflight requests for registered routes, with 204 if the policy allows them and 403 otherwise.
+    /// Preflight requests for other paths are routed like any request, e.g. to the passthrough upstream.
+    /// # Arguments
+    /// * `session` - A mutable reference to the session object.
+    /// # Returns
+    /// * Whether the request was a preflight request and has been answered.
+    pub(crate) async fn handle_preflight(&self, session: &mut Session) -> pingora::Result<bool> {
+        if !Cors::is_preflight(session.req_header()) {
+            return Ok(false);
+        }
+
+        let request_summary = ProxyHandler::extract_request_summary(session);
+        let methods = self.router.allowed_methods(&request_summary.path);
+        if methods.is_empty() {
+            return Ok(false);
+        }
+
+        match self.cors.preflight_headers(session.req_header(), &methods) {
+            Some(headers) => ProxyHandler::set_headers(StatusCode::NO_CONTENT, Some(0), headers, session).await?,
+            None => ProxyHandler::set_headers(StatusCode::FORBIDDEN, Some(0), HeaderMap::new(), session).await?,
+        }
+        session.write_response_body(None, true).await?;
+        Ok(true)
+    }
+
+    /// Returns the CORS headers of the response to the request, e.g. to add them to upstream responses.
+    pub(crate) fn cors_headers(&self, session: &Session) -> HeaderMap {
+        self.cors.response_headers(session.req_header())
+    }
+
+    /// Writes the response header, with the Content-Length of the body or with chunked framing when it is streamed.
+    async fn set_headers(response_status: StatusCode, content_length: Option<usize>, headers: HeaderMap, session: &mut Session) -> pingora::Result<()> {
         let mut header = ResponseHeader::build(response_status, None)?;
-        header.append_header("Content-Length", body_bytes.len().to_string()).unwrap();
-        // access headers below are needed to pass browser's policy
-        header.append_header("Access-Control-Allow-Origin", "*".to_string()).unwrap();
-        header.append_header("Access-Control-Allow-Methods", "*".to_string()).unwrap();
-        header.append_header("Access-Control-Allow-Headers", "*".to_string()).unwrap();
-        header.append_header("Access-Control-Max-Age", "86400".to_string()).unwrap();
+        match content_length {
+            Some(length) => header.append_header(CONTENT_LENGTH, length)?,
+            None => header.append_header(TRANSFER_ENCODING, "chunked")?,
+        };
+        for (name, value) in headers.iter() {
+            // the framing of the body is the proxy's business
+            if name == CONTENT_LENGTH || name == TRANSFER_ENCODING {
+                continue;
+            }
+            header.append_header(name.clone(), value.clone())?;
+        }
         session.write_response_header_ref(&header).await
     }
-}
\ No newline at end of file
+
+    /// Writes a response, merging its headers with the CORS headers of the request,
+    /// and streaming its body chunk by chunk if it has a stream.
+    /// 405 responses and answers to OPTIONS requests list the methods of the path in an Allow header.
+    /// HEAD responses keep only the header of the body they would have sent.
+    pub(crate) async fn write_response(&self, response: Response, session: &mut Session) -> pingora::Result<()> {
+        let method = session.req_header().method.clone();
+
+        let mut headers = response.headers;
+        merge_cors_headers(&mut headers, &self.cors_headers(session));
+        if response.status == StatusCode::METHOD_NOT_ALLOWED || method == Method::OPTIONS {
+            if let Some(allow) = self.allow_header(session).and_then(|allow| HeaderValue::from_str(&allow).ok()) {
+                headers.insert(ALLOW, allow);
+            }
+        }
+
+        let Some(mut stream) = response.stream else {
+            let body = response.body.unwrap_or_default();
+            // serialized bodies set their own Content-Type, see IntoResponse, anything else is opaque bytes
+            if !body.is_empty() && !headers.contains_key(CONTENT_TYPE) {
+                headers.insert(CONTENT_TYPE, HeaderValue::from_static(OCTET_STREAM));
+            }
+            ProxyHandler::set_headers(response.status, Some(body.len()), headers, session).await?;
+            let body = if method == Method::HEAD { None } else { Some(Bytes::from(body)) };
+            return session.write_response_body(body, true).await;
+        };
+
+        if !headers.contains_key(CONTENT_TYPE) {
+            headers.insert(CONTENT_TYPE, HeaderValue::from_static(OCTET_STREAM));
+        }
+        ProxyHandler::set_headers(response.status, None, headers, session).await?;
+        if method == Method::HEAD {
+            return session.write_response_body(None, true).await;
+        }
+
+        loop {
+            match stream.next_chunk().await {
+                Ok(Some(chunk)) => session.write_response_body(Some(Bytes::from(chunk)), false).await?,
+                Ok(None) => return session.write_response_body(None, true).await,
+                Err(err) => {
+                    // the response header is already sent, so the only option left is to abort
+                    error!("unable to stream response body: {}", err);
+                    return Err(Error::explain(ErrorType::WriteError, "response body stream failed"));
+                }
+            }
+        }
+    }
+}
+
+/// Merges the CORS headers of the proxy into the headers of a response. The proxy's CORS policy takes
+/// precedence over the handlers', except for Vary, whose values are appended to the handlers' ones.
+fn merge_cors_headers(headers: &mut HeaderMap, cors_headers: &HeaderMap) {
+    for name in cors_headers.keys() {
+        if name != VARY {
+            headers.remove(name);
+        }
+    }
+    for (name, value) in cors_headers.iter() {
+        headers.append(name.clone(), value.clone());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::header::{ACCESS_CONTROL_ALLOW_ORIGIN, CACHE_CONTROL};
+    use super::*;
+
+    #[test]
+    fn cors_headers_replace_the_handlers_but_vary_is_appended() {
+        let mut headers = HeaderMap::new();
+        headers.insert(VARY, HeaderValue::from_static("Accept"));
+        headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, HeaderValue::from_static("https://handler.example.com"));
+        headers.insert(CACHE_CONTROL, HeaderValue::from_static("no-store"));
+
+        let mut cors_headers = HeaderMap::new();
+        cors_headers.insert(VARY, HeaderValue::from_static("Origin"));
+        cors_headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, HeaderValue::from_static("https://app.example.com"));
+        merge_cors_headers(&mut headers, &cors_headers);
+
+        assert_eq!(headers.get_all(VARY).iter().collect::<Vec<_>>(), vec!["Accept", "Origin"]);
+        assert_eq!(headers.get_all(ACCESS_CONTROL_ALLOW_ORIGIN).iter().collect::<Vec<_>>(), vec!["https://app.example.com"]);
+        assert_eq!(headers.get(CACHE_CONTROL).unwrap(), "no-store");
+    }
+}
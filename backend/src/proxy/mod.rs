@@ -1,67 +1,302 @@
 use async_trait::async_trait;
 use bytes::Bytes;
-use std::net::ToSocketAddrs;
-use log::{info};
+use http::header::{CONTENT_LENGTH, CONTENT_TYPE, TRANSFER_ENCODING, VARY};
+use http::HeaderValue;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use log::{error, info};
 use pingora::upstreams::peer::HttpPeer;
-use pingora::{Result};
-use pingora::http::{StatusCode};
+use pingora::{Error, ErrorType, Result};
+use pingora::http::{Method, RequestHeader, ResponseHeader, StatusCode};
 use pingora::proxy::{ProxyHttp, Session};
+pub(crate) mod cors;
 pub(crate) mod handler;
+pub(crate) mod upstream;
 use crate::proxy::handler::{ProxyHandler};
+use crate::proxy::upstream::UpstreamGroup;
+use crate::error::WgpError;
+use crate::format::Format;
+use crate::router::body::BodyDecoder;
+use crate::router::types::{IntoResponse, RouteMode};
+
+/// UpstreamCipher lets the proxy protect traffic forwarded to the upstream with the client's nTor session,
+/// so that an unmodified upstream only ever sees plaintext while the client only ever sees ciphertext.
+pub trait UpstreamCipher {
+    /// Checks that the request can be protected, e.g. that its nTor session exists, before it is forwarded.
+    fn verify_request(&self, request_header: &RequestHeader) -> Result<(), WgpError>;
+    /// Returns the decoder decrypting the request body as its chunks arrive, before they are sent to the upstream:
+    /// frame by frame if the client sent it in frames, or at once if it is a single message.
+    fn request_decoder(&self, request_header: &RequestHeader) -> Result<Box<dyn BodyDecoder>, WgpError>;
+    /// Encrypts a chunk of the upstream response body into the frame `index` as soon as it is received,
+    /// `last` being set for the final frame so that the client can tell a complete body from a truncated one.
+    fn encrypt_response_frame(&self, request_header: &RequestHeader, chunk: Vec<u8>, index: u64, last: bool) -> Result<Vec<u8>, WgpError>;
+    /// Content type of the encrypted response frames, which replaces the upstream's.
+    fn response_content_type(&self, request_header: &RequestHeader) -> HeaderValue;
+}
+
+/// ProxyContext is the per-request state kept across pingora's filters.
+#[derive(Default)]
+pub struct ProxyContext {
+    /// upstream group the request is forwarded to, if it is not handled locally
+    upstream: Option<String>,
+    /// whether the request is forwarded to the upstream with nTor protection
+    encrypted: bool,
+    /// whether the request announces a body, bodiless requests such as GET being forwarded as they are
+    request_has_body: bool,
+    /// decrypts the request body of encrypted routes, when the request announces one
+    request_decoder: Option<Box<dyn BodyDecoder>>,
+    /// size of the request body forwarded so far
+    request_body_bytes: usize,
+    /// whether the upstream response has a body to encrypt, which responses to HEAD requests and 204 or 304 responses lack
+    response_has_body: bool,
+    /// number of encrypted response frames sent so far
+    response_frames: u64,
+}
+
+impl ProxyContext {
+    /// Decodes a chunk of the request body with the request's decoder, e.g. decrypts the frames it completes.
+    /// # Returns
+    /// * The decoded chunk, None if nothing could be decoded yet, or the chunk as it is if the body is not decoded.
+    ///   Bodies announced but empty are forwarded as they are, there is nothing to decrypt.
+    async fn decode_request_body(&mut self, chunk: Option<Bytes>, end_of_stream: bool) -> Result<Option<Bytes>, WgpError> {
+        let Some(decoder) = self.request_decoder.as_mut() else {
+            return Ok(chunk);
+        };
+
+        if end_of_stream && self.request_body_bytes == 0 {
+            return Ok(None);
+        }
+
+        let chunk = chunk.map(|chunk| chunk.to_vec()).unwrap_or_default();
+        let decoded = decoder.decode(chunk, end_of_stream).await?;
+        Ok(Some(Bytes::from(decoded)).filter(|decoded| !decoded.is_empty()))
+    }
+}
+
+/// Tells whether the request announces a body, with a positive Content-Length or with chunked framing.
+fn announces_body(request_header: &RequestHeader) -> bool {
+    let header = |name| request_header.headers.get(name).and_then(|value| value.to_str().ok());
+
+    header(CONTENT_LENGTH)
+        .and_then(|length| length.trim().parse::<usize>().ok())
+        .is_some_and(|length| length > 0)
+        || header(TRANSFER_ENCODING).is_some_and(|encoding| encoding.to_ascii_lowercase().contains("chunked"))
+}
+
+/// Tells whether the response to a `method` request with `status` has a body,
+/// which responses to HEAD requests and 1xx, 204 and 304 responses never have.
+fn response_has_body(method: &Method, status: StatusCode) -> bool {
+    *method != Method::HEAD
+        && !status.is_informational()
+        && status != StatusCode::NO_CONTENT
+        && status != StatusCode::NOT_MODIFIED
+}
 
 pub struct Proxy<T> {
-    addr: std::net::SocketAddr,
-    handler: ProxyHandler<T>,
+    upstreams: HashMap<String, UpstreamGroup>,
+    handler: ProxyHandler,
+    // protects the traffic of encrypted upstream routes
+    cipher: Arc<T>,
 }
 
-impl<T: Sync> Proxy<T> {
-    pub(crate) fn new(upstream_host: String, upstream_port: u16, handler: ProxyHandler<T>) -> Self {
-        let addr = (upstream_host, upstream_port)
-            .to_socket_addrs()
-            .unwrap()
-            .next()
-            .unwrap();
+impl<T: Send + Sync> Proxy<T> {
+    pub(crate) fn new(upstreams: HashMap<String, UpstreamGroup>, handler: ProxyHandler, cipher: Arc<T>) -> Self {
+        Proxy { upstreams, handler, cipher }
+    }
+
+    /// Key used by consistent hashing: the nTor session id so that a session sticks to one peer,
+    /// falling back to the client ip.
+    fn selection_key(session: &Session) -> Vec<u8> {
+        if let Some(session_id) = session.req_header().headers.get("nTor_session_id") {
+            return session_id.as_bytes().to_vec();
+        }
+
+        session.client_addr()
+            .and_then(|addr| addr.as_inet())
+            .map(|addr| addr.ip().to_string().into_bytes())
+            .unwrap_or_default()
+    }
+}
 
-        Proxy { addr, handler }
+impl<T: Send + Sync + UpstreamCipher> Proxy<T> {
+    /// Converts an error raised once pingora is forwarding the request into a pingora error,
+    /// so that pingora answers with its status.
+    fn forward_error(err: WgpError) -> Box<Error> {
+        Error::explain(ErrorType::HTTPStatus(err.status().as_u16()), err.to_string())
     }
 }
 
 #[async_trait]
-impl<T: Sync> ProxyHttp for Proxy<T> {
-    type CTX = ();
-    fn new_ctx(&self) -> Self::CTX { () }
+impl<T: Send + Sync + UpstreamCipher> ProxyHttp for Proxy<T> {
+    type CTX = ProxyContext;
+    fn new_ctx(&self) -> Self::CTX { ProxyContext::default() }
 
     async fn upstream_peer(
         &self,
-        _session: &mut Session,
-        _ctx: &mut Self::CTX,
+        session: &mut Session,
+        ctx: &mut Self::CTX,
     ) -> Result<Box<HttpPeer>> {
-        let peer: Box<HttpPeer> = Box::new(HttpPeer::new(self.addr, false, self.addr.ip().to_string()));
+        let group = ctx.upstream.as_ref()
+            .and_then(|name| self.upstreams.get(name))
+            .ok_or_else(|| Error::explain(ErrorType::HTTPStatus(502), "unknown upstream group"))?;
+
+        let backend = group.select(&Self::selection_key(session))
+            .ok_or_else(|| Error::explain(ErrorType::HTTPStatus(503), "no healthy upstream peer"))?;
+
+        let sni = backend.addr.to_string();
+        let peer: Box<HttpPeer> = Box::new(HttpPeer::new(backend, false, sni));
         Ok(peer)
     }
 
-    async fn request_filter(&self, session: &mut Session, _ctx: &mut Self::CTX) -> Result<bool>
+    async fn request_filter(&self, session: &mut Session, ctx: &mut Self::CTX) -> Result<bool>
     where
         Self::CTX: Send + Sync,
     {
-        let mut response_body_bytes = Vec::new();
+        // errors answered by the proxy itself are serialized in the format the client asked for, like the handlers' ones
+        let format = Format::negotiate(session.req_header()).unwrap_or_default();
+        if self.handler.exceeds_body_limit(session) {
+            self.handler.write_response(WgpError::PayloadTooLarge.into_response_as(format), session).await?;
+            return Ok(true);
+        }
+
+        if self.handler.handle_preflight(session).await? {
+            return Ok(true);
+        }
+
+        // let pingora forward the request to upstream_peer
+        if let Some(RouteMode::Upstream { group, encrypted }) = self.handler.route_mode(session) {
+            ctx.request_has_body = announces_body(session.req_header());
+            if encrypted {
+                // bodiless requests such as GET are forwarded as they are, with nothing to decrypt
+                let decoder = self.cipher.verify_request(session.req_header()).and_then(|()| if ctx.request_has_body {
+                    self.cipher.request_decoder(session.req_header()).map(Some)
+                } else {
+                    Ok(None)
+                });
+                match decoder {
+                    Ok(decoder) => ctx.request_decoder = decoder,
+                    Err(err) => {
+                        self.handler.write_response(err.into_response_as(format), session).await?;
+                        return Ok(true);
+                    }
+                }
+            }
+
+            ctx.upstream = Some(group);
+            ctx.encrypted = encrypted;
+            return Ok(false);
+        }
 
         // validate request
-        let mut response_status = self.handler.validate_request(session);
-        if response_status == StatusCode::OK {
+        let response = match self.handler.validate_request(session) {
             // handle request
-            let wgp_response = self.handler.handle_request(session).await;
-            if let Some(body) = wgp_response.body {
-                response_body_bytes = body;
+            Ok(()) => self.handler.handle_request(session).await,
+            Err(err) => err.into_response_as(format),
+        };
+
+        self.handler.write_response(response, session).await?;
+
+        Ok(true)
+    }
+
+    async fn upstream_request_filter(
+        &self,
+        _session: &mut Session,
+        upstream_request: &mut RequestHeader,
+        ctx: &mut Self::CTX,
+    ) -> Result<()> {
+        // the decrypted body has a different length, so it is re-framed as chunked
+        if ctx.encrypted && ctx.request_has_body {
+            upstream_request.remove_header("Content-Length");
+            upstream_request.insert_header("Transfer-Encoding", "chunked")?;
+        }
+        Ok(())
+    }
+
+    async fn request_body_filter(
+        &self,
+        _session: &mut Session,
+        body: &mut Option<Bytes>,
+        end_of_stream: bool,
+        ctx: &mut Self::CTX,
+    ) -> Result<()> {
+        if let Some(chunk) = body.as_ref() {
+            ctx.request_body_bytes += chunk.len();
+            if ctx.request_body_bytes > self.handler.max_body_bytes() {
+                return Err(Self::forward_error(WgpError::PayloadTooLarge));
             }
-            response_status = wgp_response.status;
         }
 
-        // convert json response to vec
-        ProxyHandler::<T>::set_headers(response_status, &response_body_bytes, session).await?;
-        session.write_response_body(Some(Bytes::from(response_body_bytes)), true).await?;
+        // encrypted bodies are decrypted as their frames arrive, a body sent as a single message once it is complete
+        *body = ctx.decode_request_body(body.take(), end_of_stream).await.map_err(Self::forward_error)?;
+        Ok(())
+    }
 
-        Ok(true)
+    async fn response_filter(
+        &self,
+        session: &mut Session,
+        upstream_response: &mut ResponseHeader,
+        ctx: &mut Self::CTX,
+    ) -> Result<()> {
+        // the proxy's CORS policy applies to upstream responses too
+        for (name, value) in self.handler.cors_headers(session).iter() {
+            if name == VARY {
+                upstream_response.append_header(name.clone(), value.clone())?;
+            } else {
+                upstream_response.insert_header(name.clone(), value.clone())?;
+            }
+        }
+
+        // the encrypted frames have a different length, so the body is re-framed as chunked,
+        // while responses without a body keep their framing and get no frame, not even the last one
+        ctx.response_has_body = response_has_body(&session.req_header().method, upstream_response.status);
+        if ctx.encrypted && ctx.response_has_body {
+            upstream_response.remove_header("Content-Length");
+            upstream_response.insert_header("Transfer-Encoding", "chunked")?;
+            upstream_response.insert_header(CONTENT_TYPE, self.cipher.response_content_type(session.req_header()))?;
+        }
+        Ok(())
+    }
+
+    fn response_body_filter(
+        &self,
+        session: &mut Session,
+        body: &mut Option<Bytes>,
+        end_of_stream: bool,
+        ctx: &mut Self::CTX,
+    ) -> Result<Option<Duration>> {
+        if !ctx.encrypted || !ctx.response_has_body {
+            return Ok(None);
+        }
+
+        // each chunk is encrypted into a frame as it arrives, the end of the stream being marked by a last, empty frame
+        let mut frames = Vec::new();
+        let mut chunks = Vec::new();
+        if let Some(chunk) = body.take().filter(|chunk| !chunk.is_empty()) {
+            chunks.push((chunk.to_vec(), false));
+        }
+        if end_of_stream {
+            chunks.push((Vec::new(), true));
+        }
+
+        for (chunk, last) in chunks {
+            match self.cipher.encrypt_response_frame(session.req_header(), chunk, ctx.response_frames, last) {
+                Ok(frame) => frames.extend_from_slice(&frame),
+                Err(err) => {
+                    // the response header is already sent, so the only option left is to abort
+                    error!("unable to encrypt upstream response: {}", err);
+                    return Err(Self::forward_error(err));
+                }
+            }
+            ctx.response_frames += 1;
+        }
+
+        if !frames.is_empty() {
+            *body = Some(Bytes::from(frames));
+        }
+
+        Ok(None)
     }
 
     async fn logging(
@@ -77,3 +312,93 @@ impl<T: Sync> ProxyHttp for Proxy<T> {
         info!("{} response code: {response_code}", self.request_summary(session, ctx));
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: &str, headers: &[(&str, &str)]) -> RequestHeader {
+        let mut request = RequestHeader::build(method, b"/archive/1", None).unwrap();
+        for (name, value) in headers {
+            request.insert_header(name.to_string(), value.to_string()).unwrap();
+        }
+        request
+    }
+
+    /// Decodes pairs of bytes into their first byte, like frames decrypted once complete.
+    struct Pairs(Vec<u8>);
+
+    #[async_trait]
+    impl BodyDecoder for Pairs {
+        async fn decode(&mut self, chunk: Vec<u8>, end_of_stream: bool) -> Result<Vec<u8>, WgpError> {
+            self.0.extend(chunk);
+            if end_of_stream && self.0.len() % 2 == 1 {
+                return Err(WgpError::DecryptFailed);
+            }
+            let complete = self.0.len() - self.0.len() % 2;
+            Ok(self.0.drain(..complete).step_by(2).collect())
+        }
+    }
+
+    /// The context of an encrypted request forwarded to the upstream, whose body counts `read` bytes so far.
+    fn encrypted_context(request_header: &RequestHeader, read: usize) -> ProxyContext {
+        let request_has_body = announces_body(request_header);
+        ProxyContext {
+            encrypted: true,
+            request_has_body,
+            request_decoder: request_has_body.then(|| Box::new(Pairs(Vec::new())) as Box<dyn BodyDecoder>),
+            request_body_bytes: read,
+            ..ProxyContext::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn encrypted_get_is_forwarded_without_decrypting() {
+        let request = request("GET", &[("nTor_session_id", "session")]);
+        let mut ctx = encrypted_context(&request, 0);
+
+        assert!(!announces_body(&request));
+        assert_eq!(ctx.decode_request_body(None, true).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn encrypted_body_is_decrypted_as_its_frames_arrive() {
+        let request = request("POST", &[("Transfer-Encoding", "chunked")]);
+        let mut ctx = encrypted_context(&request, 3);
+        assert_eq!(ctx.decode_request_body(Some(Bytes::from_static(b"aAb")), false).await.unwrap(), Some(Bytes::from_static(b"a")));
+        assert_eq!(ctx.decode_request_body(Some(Bytes::from_static(b"B")), false).await.unwrap(), Some(Bytes::from_static(b"b")));
+        assert_eq!(ctx.decode_request_body(None, true).await.unwrap(), None);
+
+        let mut ctx = encrypted_context(&request, 1);
+        assert_eq!(ctx.decode_request_body(Some(Bytes::from_static(b"c")), false).await.unwrap(), None);
+        assert!(matches!(ctx.decode_request_body(None, true).await, Err(WgpError::DecryptFailed)));
+
+        // a chunked body may turn out empty, it is forwarded as it is
+        let mut ctx = encrypted_context(&request, 0);
+        assert_eq!(ctx.decode_request_body(None, true).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn plaintext_body_is_forwarded_as_it_is() {
+        let mut ctx = ProxyContext::default();
+        assert_eq!(ctx.decode_request_body(Some(Bytes::from_static(b"abc")), false).await.unwrap(), Some(Bytes::from_static(b"abc")));
+    }
+
+    #[test]
+    fn responses_to_head_and_204_or_304_responses_have_no_body() {
+        assert!(response_has_body(&Method::GET, StatusCode::OK));
+        assert!(response_has_body(&Method::POST, StatusCode::NOT_FOUND));
+        assert!(!response_has_body(&Method::HEAD, StatusCode::OK));
+        assert!(!response_has_body(&Method::GET, StatusCode::NO_CONTENT));
+        assert!(!response_has_body(&Method::GET, StatusCode::NOT_MODIFIED));
+        assert!(!response_has_body(&Method::GET, StatusCode::CONTINUE));
+    }
+
+    #[test]
+    fn announces_body_with_positive_length_or_chunked_framing() {
+        assert!(announces_body(&request("POST", &[("Content-Length", "6")])));
+        assert!(announces_body(&request("POST", &[("Transfer-Encoding", "gzip, chunked")])));
+        assert!(!announces_body(&request("DELETE", &[("Content-Length", "0")])));
+        assert!(!announces_body(&request("HEAD", &[])));
+    }
+}
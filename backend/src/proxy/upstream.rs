@@ -0,0 +1,93 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use pingora::lb::{Backend, LoadBalancer};
+use pingora::lb::health_check::{HealthCheck, HttpHealthCheck, TcpHealthCheck};
+use pingora::lb::selection::{BackendIter, BackendSelection, RoundRobin};
+use pingora::lb::selection::consistent::KetamaHashing;
+use pingora::services::background::background_service;
+use pingora::services::Service;
+use crate::config::{HealthCheckConfig, HealthCheckKind, UpstreamGroupConfig, UpstreamSelection};
+
+/// how many backends are tried when skipping unhealthy ones
+const MAX_SELECT_ITERATIONS: usize = 256;
+
+/// UpstreamGroup is a load balanced set of upstream peers that routes can forward to by name.
+/// Peers failing their health check are ejected from the selection until they recover.
+pub(crate) enum UpstreamGroup {
+    RoundRobin(Arc<LoadBalancer<RoundRobin>>),
+    ConsistentHash(Arc<LoadBalancer<KetamaHashing>>),
+}
+
+impl UpstreamGroup {
+    /// Builds all configured upstream groups keyed by name.
+    /// # Returns
+    /// * The groups, and the background services running their health checks which must be added to the server.
+    pub(crate) fn from_config(groups: &[UpstreamGroupConfig]) -> (HashMap<String, UpstreamGroup>, Vec<Box<dyn Service>>) {
+        let mut upstreams = HashMap::new();
+        let mut services = Vec::new();
+
+        for config in groups {
+            let (group, service) = match config.selection {
+                UpstreamSelection::RoundRobin => {
+                    let (lb, service) = Self::build::<RoundRobin>(config);
+                    (UpstreamGroup::RoundRobin(lb), service)
+                }
+                UpstreamSelection::ConsistentHash => {
+                    let (lb, service) = Self::build::<KetamaHashing>(config);
+                    (UpstreamGroup::ConsistentHash(lb), service)
+                }
+            };
+
+            upstreams.insert(config.name.clone(), group);
+            services.push(service);
+        }
+
+        (upstreams, services)
+    }
+
+    fn build<S>(config: &UpstreamGroupConfig) -> (Arc<LoadBalancer<S>>, Box<dyn Service>)
+    where
+        S: BackendSelection + Send + Sync + 'static,
+        S::Iter: BackendIter,
+    {
+        let mut lb = LoadBalancer::<S>::try_from_iter(config.addresses.iter().map(|addr| addr.as_str()))
+            .expect("Failed to resolve upstream addresses");
+
+        if let Some(health_check) = &config.health_check {
+            lb.set_health_check(Self::health_check(health_check));
+            lb.health_check_frequency = Some(Duration::from_secs(health_check.interval_secs));
+        }
+
+        let service = background_service(&format!("upstream {} health check", config.name), lb);
+        let lb = service.task();
+        (lb, Box::new(service))
+    }
+
+    fn health_check(config: &HealthCheckConfig) -> Box<dyn HealthCheck + Send + Sync + 'static> {
+        match config.kind {
+            HealthCheckKind::Tcp => {
+                let mut health_check = TcpHealthCheck::new();
+                health_check.consecutive_success = config.consecutive_success;
+                health_check.consecutive_failure = config.consecutive_failure;
+                health_check
+            }
+            HealthCheckKind::Http => {
+                let mut health_check = HttpHealthCheck::new(&config.host, false);
+                health_check.req.set_uri(config.path.parse().expect("Invalid health check path"));
+                health_check.consecutive_success = config.consecutive_success;
+                health_check.consecutive_failure = config.consecutive_failure;
+                Box::new(health_check)
+            }
+        }
+    }
+
+    /// Selects a healthy peer. `key` is only used by consistent hashing,
+    /// so that requests with the same key stick to the same peer.
+    pub(crate) fn select(&self, key: &[u8]) -> Option<Backend> {
+        match self {
+            UpstreamGroup::RoundRobin(lb) => lb.select(key, MAX_SELECT_ITERATIONS),
+            UpstreamGroup::ConsistentHash(lb) => lb.select(key, MAX_SELECT_ITERATIONS),
+        }
+    }
+}
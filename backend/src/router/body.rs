@@ -0,0 +1,54 @@
+use std::path::Path;
+use async_trait::async_trait;
+use tokio::fs::File;
+use tokio::io::AsyncReadExt;
+use crate::error::WgpError;
+
+/// Size of the chunks a file is streamed with.
+const FILE_CHUNK_SIZE: usize = 64 * 1024;
+
+/// BodyStream produces a response body chunk by chunk, so that large bodies such as images
+/// are never held in memory at once. The proxy sends it with chunked transfer encoding.
+#[async_trait]
+pub trait BodyStream: Send {
+    /// Returns the next chunk of the body, or None once the body is complete.
+    /// An error aborts the response, whose header is already sent by then.
+    async fn next_chunk(&mut self) -> Result<Option<Vec<u8>>, String>;
+}
+
+/// BodyDecoder transforms a request body as its chunks arrive, e.g. decrypting it frame by frame,
+/// so that handlers reading it with `ContextTrait::read_body_chunk` never see it encoded, see `ContextTrait::set_body_decoder`.
+#[async_trait]
+pub trait BodyDecoder: Send + Sync {
+    /// Decodes a chunk of the body, returning whatever can be decoded so far, which may be nothing until more arrives.
+    /// `end_of_stream` is set with the last chunk, which may be empty.
+    async fn decode(&mut self, chunk: Vec<u8>, end_of_stream: bool) -> Result<Vec<u8>, WgpError>;
+}
+
+/// FileStream streams the content of a file.
+pub struct FileStream {
+    file: File,
+}
+
+impl FileStream {
+    pub async fn open(path: impl AsRef<Path>) -> Result<Self, String> {
+        let path = path.as_ref();
+        let file = File::open(path).await
+            .map_err(|err| format!("Failed to open file {}: {}", path.display(), err))?;
+        Ok(FileStream { file })
+    }
+}
+
+#[async_trait]
+impl BodyStream for FileStream {
+    async fn next_chunk(&mut self) -> Result<Option<Vec<u8>>, String> {
+        let mut chunk = vec![0; FILE_CHUNK_SIZE];
+        let read = self.file.read(&mut chunk).await.map_err(|err| err.to_string())?;
+        if read == 0 {
+            return Ok(None);
+        }
+
+        chunk.truncate(read);
+        Ok(Some(chunk))
+    }
+}
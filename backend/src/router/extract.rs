@@ -0,0 +1,58 @@
+use serde::de::DeserializeOwned;
+use crate::error::WgpError;
+use crate::router::types::ContextTrait;
+
+/// FromContext extracts the input of a typed handler from the request, see the `typed_handlers!` macro.
+/// A failed extraction answers the request with its error, without calling the handler.
+/// Tuples of extractors extract each of their elements in order, e.g. `(Body<RegisterRequestBody>, AuthUser)`.
+pub trait FromContext: Sized + Send {
+    fn from_context(ctx: &dyn ContextTrait) -> Result<Self, WgpError>;
+}
+
+/// Handlers without input take `()`.
+impl FromContext for () {
+    fn from_context(_ctx: &dyn ContextTrait) -> Result<Self, WgpError> {
+        Ok(())
+    }
+}
+
+macro_rules! tuple_from_context {
+    ($($extractor:ident),+) => {
+        impl<$($extractor: FromContext),+> FromContext for ($($extractor,)+) {
+            fn from_context(ctx: &dyn ContextTrait) -> Result<Self, WgpError> {
+                Ok(($($extractor::from_context(ctx)?,)+))
+            }
+        }
+    };
+}
+
+tuple_from_context!(A);
+tuple_from_context!(A, B);
+tuple_from_context!(A, B, C);
+tuple_from_context!(A, B, C, D);
+
+/// QueryParams deserializes the query parameters into `T`, see [`Query::deserialize`](crate::router::query::Query::deserialize).
+pub struct QueryParams<T>(pub T);
+
+impl<T: DeserializeOwned + Send> FromContext for QueryParams<T> {
+    fn from_context(ctx: &dyn ContextTrait) -> Result<Self, WgpError> {
+        ctx.query_as().map(QueryParams).map_err(WgpError::InvalidParameter)
+    }
+}
+
+/// Params deserializes the path parameters, and the first value of each query parameter, into `T`, e.g.
+/// `Params<IdParams>` for a route such as `/poems/{id}`. Numbers and booleans are parsed from their text.
+/// Path parameters take precedence over query parameters of the same name.
+pub struct Params<T>(pub T);
+
+impl<T: DeserializeOwned + Send> FromContext for Params<T> {
+    fn from_context(ctx: &dyn ContextTrait) -> Result<Self, WgpError> {
+        let encoded = form_urlencoded::Serializer::new(String::new())
+            .extend_pairs(ctx.params())
+            .finish();
+
+        serde_html_form::from_str(&encoded)
+            .map(Params)
+            .map_err(|err| WgpError::InvalidParameter(err.to_string()))
+    }
+}
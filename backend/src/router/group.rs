@@ -0,0 +1,214 @@
+use std::sync::Arc;
+use pingora::http::Method;
+use crate::router::Router;
+use crate::router::middleware::Middleware;
+use crate::router::types::Handler;
+
+/// RouteGroup registers routes under a common path prefix, surrounding each route's own handlers
+/// with the group's: `before` handlers run first and `after` handlers run last, as long as they answer with 200,
+/// and the whole is wrapped by the group's middleware, which sees every response, e.g.
+/// ```ignore
+/// let mut api = router.group("/api/v1")
+///     .wrap(middleware![msg_handler; WGPMessageHandler::ntor_encrypt])
+///     .before(handlers![msg_handler; WGPMessageHandler::authentication_middleware]);
+/// api.get("/poems".to_string(), typed_handlers![msg_handler; WGPMessageHandler::get_poems]);
+/// ```
+pub struct RouteGroup<'r> {
+    router: &'r mut Router,
+    prefix: String,
+    middleware: Vec<Arc<dyn Middleware>>,
+    before: Vec<Arc<dyn Handler>>,
+    after: Vec<Arc<dyn Handler>>,
+    // after handlers of the enclosing groups, which run once this group's are done
+    outer_after: Vec<Arc<dyn Handler>>,
+}
+
+impl<'r> RouteGroup<'r> {
+    pub(super) fn new(router: &'r mut Router, prefix: &str) -> Self {
+        RouteGroup {
+            router,
+            prefix: prefix.to_string(),
+            middleware: Vec::new(),
+            before: Vec::new(),
+            after: Vec::new(),
+            outer_after: Vec::new(),
+        }
+    }
+
+    /// Adds middleware wrapping every route of the group, the first one being the outermost.
+    pub fn wrap(mut self, middleware: Box<[Arc<dyn Middleware>]>) -> Self {
+        self.middleware.extend(middleware);
+        self
+    }
+
+    /// Adds handlers running before the handlers of every route of the group, in order.
+    pub fn before(mut self, handlers: Box<[Arc<dyn Handler>]>) -> Self {
+        self.before.extend(handlers);
+        self
+    }
+
+    /// Adds handlers running after the handlers of every route of the group, in order.
+    pub fn after(mut self, handlers: Box<[Arc<dyn Handler>]>) -> Self {
+        self.after.extend(handlers);
+        self
+    }
+
+    /// Creates a nested group, whose prefix and middleware extend the ones of this group.
+    /// The middleware of this group wraps the nested group's, its `before` handlers run before the nested group's,
+    /// and its `after` handlers after them.
+    pub fn group(&mut self, prefix: &str) -> RouteGroup<'_> {
+        RouteGroup {
+            router: &mut *self.router,
+            prefix: format!("{}/{}", self.prefix, prefix),
+            middleware: self.middleware.clone(),
+            before: self.before.clone(),
+            after: Vec::new(),
+            outer_after: self.after.iter().chain(&self.outer_after).cloned().collect(),
+        }
+    }
+
+    /// Registers handlers for `method` on a route pattern relative to the group's prefix,
+    /// see [`Router::add`].
+    pub fn add(&mut self, method: Method, path: String, handlers: Box<[Arc<dyn Handler>]>) {
+        self.add_route(method, path, handlers, false);
+    }
+
+    /// Registers handlers reading the request body themselves, see [`Router::add_streaming`].
+    pub fn add_streaming(&mut self, method: Method, path: String, handlers: Box<[Arc<dyn Handler>]>) {
+        self.add_route(method, path, handlers, true);
+    }
+
+    fn add_route(&mut self, method: Method, path: String, handlers: Box<[Arc<dyn Handler>]>, streaming: bool) {
+        let handlers = self.before.iter().cloned()
+            .chain(handlers)
+            .chain(self.after.iter().cloned())
+            .chain(self.outer_after.iter().cloned())
+            .collect();
+
+        // empty segments are ignored when matching, so joining with a slash is always safe
+        self.router.add_wrapped(method, format!("{}/{}", self.prefix, path), self.middleware.clone().into_boxed_slice(), handlers, streaming);
+    }
+
+    pub fn post(&mut self, path: String, handlers: Box<[Arc<dyn Handler>]>) {
+        self.add(Method::POST, path, handlers);
+    }
+
+    pub fn get(&mut self, path: String, handlers: Box<[Arc<dyn Handler>]>) {
+        self.add(Method::GET, path, handlers);
+    }
+
+    pub fn put(&mut self, path: String, handlers: Box<[Arc<dyn Handler>]>) {
+        self.add(Method::PUT, path, handlers);
+    }
+
+    pub fn delete(&mut self, path: String, handlers: Box<[Arc<dyn Handler>]>) {
+        self.add(Method::DELETE, path, handlers);
+    }
+
+    pub fn patch(&mut self, path: String, handlers: Box<[Arc<dyn Handler>]>) {
+        self.add(Method::PATCH, path, handlers);
+    }
+
+    pub fn head(&mut self, path: String, handlers: Box<[Arc<dyn Handler>]>) {
+        self.add(Method::HEAD, path, handlers);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::{HeaderName, HeaderValue};
+    use pingora::http::StatusCode;
+    use super::*;
+    use crate::router::middleware::middleware_fn;
+    use crate::router::types::{handler_fn, Response, RouteMode, TestContext};
+
+    async fn call(router: &Router, method: Method, path: &str) -> Response {
+        router.call_handler(&mut TestContext::new(method, path)).await
+    }
+
+    fn body(response: &Response) -> &str {
+        std::str::from_utf8(response.body.as_deref().unwrap_or_default()).unwrap()
+    }
+
+    fn group_header(response: &Response) -> Option<&str> {
+        response.headers.get("x-group").and_then(|value| value.to_str().ok())
+    }
+
+    /// Answers with the `id` path parameter and the handlers that ran before, recorded by `trace`.
+    fn echo() -> Box<[Arc<dyn Handler>]> {
+        Box::new([handler_fn(|ctx| Box::pin(async move {
+            let body = format!("{}|{}", ctx.param("id").map(String::as_str).unwrap_or("-"), ctx.get("trace").map(String::as_str).unwrap_or("-"));
+            Response::new(StatusCode::OK, Some(body.into_bytes()))
+        }))])
+    }
+
+    fn trace(name: &'static str) -> Box<[Arc<dyn Handler>]> {
+        Box::new([handler_fn(move |ctx| Box::pin(async move {
+            let trace = match ctx.get("trace") {
+                Some(trace) => format!("{trace},{name}"),
+                None => name.to_string(),
+            };
+            ctx.set("trace".to_string(), trace);
+            Response::new(StatusCode::OK, None)
+        }))])
+    }
+
+    fn tag(value: &'static str) -> Box<[Arc<dyn Middleware>]> {
+        Box::new([middleware_fn(move |ctx, next| Box::pin(async move {
+            next.run(ctx).await.with_header(HeaderName::from_static("x-group"), HeaderValue::from_static(value))
+        }))])
+    }
+
+    #[tokio::test]
+    async fn prefixed_group_resolves_routes_and_middleware() {
+        let mut router = Router::new();
+        router.get("/poems/{id}".to_string(), echo());
+        {
+            let mut api = router.group("/api/v1").wrap(tag("api")).before(trace("api"));
+            api.get("/poems/{id}".to_string(), echo());
+
+            let mut admin = api.group("/admin").before(trace("admin"));
+            admin.get("/poems/{id}".to_string(), echo());
+        }
+
+        assert_eq!(router.route_mode(&Method::GET, "/api/v1/poems/7"), Some(RouteMode::Local));
+        assert_eq!(router.route_mode(&Method::GET, "/api/poems/7"), None);
+        assert_eq!(router.allowed_methods("/api/v1/admin/poems/7"), vec![Method::GET, Method::HEAD, Method::OPTIONS]);
+
+        let response = call(&router, Method::GET, "/api/v1/poems/7").await;
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(body(&response), "7|api");
+        assert_eq!(group_header(&response), Some("api"));
+
+        // nested groups extend the prefix, the middleware and the before handlers of their parent
+        let response = call(&router, Method::GET, "/api/v1/admin/poems/8").await;
+        assert_eq!(body(&response), "8|api,admin");
+        assert_eq!(group_header(&response), Some("api"));
+
+        // routes outside the group are left alone
+        let response = call(&router, Method::GET, "/poems/9").await;
+        assert_eq!(body(&response), "9|-");
+        assert_eq!(group_header(&response), None);
+
+        let response = call(&router, Method::GET, "/api/v1/images").await;
+        assert_eq!(response.status, StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn only_streaming_routes_leave_the_body_to_their_handlers() {
+        let mut router = Router::new();
+        router.add_streaming(Method::PUT, "/images/{id}/content".to_string(), echo());
+        {
+            let mut api = router.group("/api").before(trace("api"));
+            api.add_streaming(Method::POST, "/uploads".to_string(), echo());
+            api.post("/poems".to_string(), echo());
+        }
+        router.upstream(Method::POST, "/archive".to_string(), "archive".to_string(), true);
+
+        assert!(router.streams_body(&Method::PUT, "/images/7/content"));
+        assert!(router.streams_body(&Method::POST, "/api/uploads"));
+        assert!(!router.streams_body(&Method::POST, "/api/poems"));
+        assert!(!router.streams_body(&Method::POST, "/archive"));
+        assert!(!router.streams_body(&Method::GET, "/images/7/content"));
+    }
+}
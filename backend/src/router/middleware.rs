@@ -0,0 +1,73 @@
+use std::sync::Arc;
+use crate::router::types::{ContextTrait, Handler, HandlerFuture};
+
+/// Middleware wraps the rest of a route: it can act on the request before calling `next`,
+/// and transform the response `next` returns whatever its status, e.g. to encrypt or log it.
+/// Closures are middleware too, see [`middleware_fn`].
+pub trait Middleware: Send + Sync {
+    fn call<'a>(&'a self, ctx: &'a mut dyn ContextTrait, next: Next<'a>) -> HandlerFuture<'a>;
+}
+
+impl<F> Middleware for F
+where
+    F: for<'a> Fn(&'a mut dyn ContextTrait, Next<'a>) -> HandlerFuture<'a> + Send + Sync,
+{
+    fn call<'a>(&'a self, ctx: &'a mut dyn ContextTrait, next: Next<'a>) -> HandlerFuture<'a> {
+        self(ctx, next)
+    }
+}
+
+/// Next is the rest of the route from the point of view of a middleware:
+/// the inner middleware, then the route's handlers.
+pub struct Next<'a> {
+    middleware: &'a [Arc<dyn Middleware>],
+    endpoint: &'a dyn Handler,
+}
+
+impl<'a> Next<'a> {
+    pub(super) fn new(middleware: &'a [Arc<dyn Middleware>], endpoint: &'a dyn Handler) -> Self {
+        Next { middleware, endpoint }
+    }
+
+    /// Runs the rest of the route and returns its response.
+    pub fn run<'c>(self, ctx: &'c mut dyn ContextTrait) -> HandlerFuture<'c>
+    where
+        'a: 'c,
+    {
+        match self.middleware.split_first() {
+            Some((first, rest)) => first.call(ctx, Next::new(rest, self.endpoint)),
+            None => self.endpoint.call(ctx),
+        }
+    }
+}
+
+/// HandleMiddleware is the middleware counterpart of `HandleMessage`.
+pub type HandleMiddleware<T> = for<'a> fn(&'a T, &'a mut dyn ContextTrait, Next<'a>) -> HandlerFuture<'a>;
+
+/// ServiceMiddleware is a middleware calling an async method of a shared service, e.g. `WGPMessageHandler::ntor_encrypt`.
+/// It is usually built with the `middleware!` macro.
+pub struct ServiceMiddleware<T> {
+    service: Arc<T>,
+    handle: HandleMiddleware<T>,
+}
+
+impl<T> ServiceMiddleware<T> {
+    pub fn new(service: Arc<T>, handle: HandleMiddleware<T>) -> Self {
+        ServiceMiddleware { service, handle }
+    }
+}
+
+impl<T: Send + Sync> Middleware for ServiceMiddleware<T> {
+    fn call<'a>(&'a self, ctx: &'a mut dyn ContextTrait, next: Next<'a>) -> HandlerFuture<'a> {
+        (self.handle)(&self.service, ctx, next)
+    }
+}
+
+/// Turns a closure into a middleware, e.g.
+/// `middleware_fn(|ctx, next| Box::pin(async move { let response = next.run(ctx).await; ... }))`.
+pub fn middleware_fn<F>(f: F) -> Arc<dyn Middleware>
+where
+    F: for<'a> Fn(&'a mut dyn ContextTrait, Next<'a>) -> HandlerFuture<'a> + Send + Sync + 'static,
+{
+    Arc::new(f)
+}
@@ -1,64 +1,106 @@
 pub mod types;
+pub mod body;
+pub mod group;
+pub mod middleware;
+pub mod query;
+pub mod extract;
+mod tree;
 
 use std::collections::HashMap;
+use std::sync::Arc;
+use http::HeaderMap;
 use pingora::http::{Method, StatusCode};
-use crate::router::types::{ContextTrait, HandleMessage, Response};
-
-pub struct Router<T> {
-    handler: T,
-    _groups: Vec<String>, // placeholder for later use
-    posts: HashMap<String, Box<[HandleMessage<T>]>>,
-    gets: HashMap<String, Box<[HandleMessage<T>]>>,
-    puts: HashMap<String, Box<[HandleMessage<T>]>>,
-    deletes: HashMap<String, Box<[HandleMessage<T>]>>,
-}
+use crate::error::WgpError;
+use crate::router::group::RouteGroup;
+use crate::router::middleware::{Middleware, Next};
+use crate::router::query::decode_segment;
+use crate::router::tree::Tree;
+use crate::router::types::{ContextTrait, Handler, HandlerFuture, IntoResponse, Response, RouteMode};
 
-impl<T> Router<T> {
-    pub fn new(handler: T) -> Self {
-        Router {
-            handler,
-            _groups: Vec::new(),
-            posts: HashMap::new(),
-            gets: HashMap::new(),
-            puts: HashMap::new(),
-            deletes: HashMap::new(),
-        }
-    }
+/// Builds the handlers of a route from async methods of a shared service (an `Arc`),
+/// which run one after another until one of them answers with another status than 200, e.g.
+/// `handlers![msg_handler; WGPMessageHandler::ntor_decrypt, WGPMessageHandler::ntor_close]`.
+#[macro_export]
+macro_rules! handlers {
+    ($service:expr; $($handler:expr),+ $(,)?) => {
+        Box::new([$(
+            ::std::sync::Arc::new($crate::router::types::ServiceHandler::new(
+                ::std::sync::Arc::clone(&$service),
+                (|handler, ctx| Box::pin($handler(handler, ctx)) as $crate::router::types::HandlerFuture<'_>)
+                    as $crate::router::types::HandleMessage<_>,
+            )) as ::std::sync::Arc<dyn $crate::router::types::Handler>
+        ),+])
+    };
+}
 
-    pub fn contains(&self, method: &Method, path: &str) -> bool {
-        match *method {
-            Method::POST => self.posts.contains_key(path),
-            Method::GET => self.gets.contains_key(path),
-            Method::PUT => self.puts.contains_key(path),
-            Method::DELETE => self.deletes.contains_key(path),
-            Method::OPTIONS => true,
-            _ => false,
-        }
-    }
+/// Builds the handlers of a route from typed async methods of a shared service (an `Arc`),
+/// which take their input from an extractor and return anything converting into a response, e.g.
+/// `async fn handle_login(&self, Body(body): Body<LoginRequestBody>) -> Result<LoginResponseBody, WgpError>`.
+/// The router extracts the input, see [`FromContext`](crate::router::extract::FromContext), and answers with the extraction error if it fails.
+/// Responses are serialized in the format negotiated with the client, see [`Format::negotiate`](crate::format::Format::negotiate).
+#[macro_export]
+macro_rules! typed_handlers {
+    ($service:expr; $($handler:expr),+ $(,)?) => {
+        Box::new([$(
+            ::std::sync::Arc::new($crate::router::types::ServiceHandler::new(
+                ::std::sync::Arc::clone(&$service),
+                (|service, ctx: &mut dyn $crate::router::types::ContextTrait| Box::pin(async move {
+                    let format = match ctx.negotiate_format() {
+                        Ok(format) => format,
+                        Err(err) => return $crate::router::types::IntoResponse::into_response(err),
+                    };
+                    match $crate::router::extract::FromContext::from_context(&*ctx) {
+                        Ok(input) => $crate::router::types::IntoResponse::into_response_as($handler(service, input).await, format),
+                        Err(err) => $crate::router::types::IntoResponse::into_response_as(err, format),
+                    }
+                }) as $crate::router::types::HandlerFuture<'_>)
+                    as $crate::router::types::HandleMessage<_>,
+            )) as ::std::sync::Arc<dyn $crate::router::types::Handler>
+        ),+])
+    };
+}
 
-    fn get_handlers(&self, method: &Method, path: &str) -> Option<&Box<[HandleMessage<T>]>> {
-        match *method {
-            Method::POST => self.posts.get(path),
-            Method::GET => self.gets.get(path),
-            Method::PUT => self.puts.get(path),
-            Method::DELETE => self.deletes.get(path),
-            _ => return None,
-        }
-    }
+/// Builds the middleware of a route from async methods of a shared service (an `Arc`),
+/// the first one being the outermost, e.g. `middleware![msg_handler; WGPMessageHandler::ntor_encrypt]`.
+#[macro_export]
+macro_rules! middleware {
+    ($service:expr; $($middleware:expr),+ $(,)?) => {
+        Box::new([$(
+            ::std::sync::Arc::new($crate::router::middleware::ServiceMiddleware::new(
+                ::std::sync::Arc::clone(&$service),
+                (|service, ctx, next| Box::pin($middleware(service, ctx, next)) as $crate::router::types::HandlerFuture<'_>)
+                    as $crate::router::middleware::HandleMiddleware<_>,
+            )) as ::std::sync::Arc<dyn $crate::router::middleware::Middleware>
+        ),+])
+    };
+}
 
-    pub fn call_handler(&self, ctx: &mut dyn ContextTrait) -> Response {
-        let method = ctx.method();
-        let path = ctx.path();
+/// A registered route is either served by local handlers or forwarded to the upstream.
+enum Route {
+    Local(LocalRoute),
+    Upstream { group: String, encrypted: bool },
+}
 
-        if method == Method::OPTIONS {
-            return Response::new(StatusCode::NO_CONTENT, None);
-        }
+/// A route served by handlers running one after another until one of them answers with another status than 200,
+/// wrapped by the route's middleware.
+struct LocalRoute {
+    middleware: Box<[Arc<dyn Middleware>]>,
+    handlers: Box<[Arc<dyn Handler>]>,
+    // the request body is left to the handlers to read chunk by chunk instead of being buffered
+    streaming: bool,
+}
 
-        if let Some(handlers) = self.get_handlers(&method, path) {
+impl Handler for LocalRoute {
+    fn call<'a>(&'a self, ctx: &'a mut dyn ContextTrait) -> HandlerFuture<'a> {
+        Box::pin(async move {
             let mut response = Response::new(StatusCode::OK, None);
-            for handler in handlers.iter() {
-                response = handler(&self.handler, ctx);
+            // headers set by every handler are kept, e.g. a cookie refreshed by an authentication handler
+            let mut headers = HeaderMap::new();
+            for handler in self.handlers.iter() {
+                response = handler.call(ctx).await;
+                headers.extend(std::mem::take(&mut response.headers));
                 if response.status != StatusCode::OK {
+                    response.headers = headers;
                     return response;
                 }
 
@@ -67,31 +109,218 @@ impl<T> Router<T> {
                 }
             }
 
+            response.headers = headers;
             response
+        })
+    }
+}
+
+/// Dispatch finds the route of a request and runs it, it is the innermost layer of the router's own middleware.
+struct Dispatch<'r> {
+    routes: &'r Tree<MethodRoutes>,
+}
+
+impl Handler for Dispatch<'_> {
+    fn call<'a>(&'a self, ctx: &'a mut dyn ContextTrait) -> HandlerFuture<'a> {
+        Box::pin(async move {
+            let method = ctx.method();
+
+            let Some(matched) = self.routes.find(ctx.path()) else {
+                return WgpError::NotFound("route".to_string()).into_response();
+            };
+
+            // registered paths answer OPTIONS themselves unless a route handles it, CORS preflights are answered by the proxy
+            if method == Method::OPTIONS && !matched.value.contains_key(&Method::OPTIONS) {
+                return Response::new(StatusCode::NO_CONTENT, None);
+            }
+
+            let Some(Route::Local(route)) = method_route(matched.value, &method) else {
+                // the path exists, only under other methods
+                return WgpError::MethodNotAllowed.into_response();
+            };
+
+            // path parameters are exposed through ctx.param, taking precedence over query parameters of the same name
+            for (key, value) in matched.params {
+                ctx.set_param(key, decode_segment(&value));
+            }
+
+            Next::new(&route.middleware, route).run(ctx).await
+        })
+    }
+}
+
+/// Routes registered for a single route pattern, keyed by HTTP method.
+type MethodRoutes = HashMap<Method, Route>;
+
+/// Finds the route of `method`, HEAD requests being served by the GET route unless a HEAD route is registered.
+/// The proxy strips the body of HEAD responses.
+fn method_route<'m>(routes: &'m MethodRoutes, method: &Method) -> Option<&'m Route> {
+    routes.get(method).or_else(|| {
+        if *method == Method::HEAD {
+            routes.get(&Method::GET)
         } else {
-            return Response::new(StatusCode::NOT_FOUND, None);
+            None
+        }
+    })
+}
+
+pub struct Router {
+    routes: Tree<MethodRoutes>,
+    // wraps every request handled locally, including the ones answered with 404
+    middleware: Vec<Arc<dyn Middleware>>,
+    // forward unmatched requests to the upstream instead of answering 404
+    passthrough: Option<String>,
+    passthrough_encrypted: bool,
+}
+
+impl Default for Router {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Router {
+            routes: Tree::new(),
+            middleware: Vec::new(),
+            passthrough: None,
+            passthrough_encrypted: false,
+        }
+    }
+
+    /// Sets the upstream group receiving unmatched requests, or None to answer them with 404,
+    /// optionally protecting them with the client's nTor session.
+    pub fn set_passthrough(&mut self, passthrough: Option<String>, encrypted: bool) {
+        self.passthrough = passthrough;
+        self.passthrough_encrypted = encrypted;
+    }
+
+    /// Tells whether a route serves `method` on `path`, OPTIONS being served on every registered path.
+    pub fn contains(&self, method: &Method, path: &str) -> bool {
+        self.routes.find(path)
+            .is_some_and(|matched| *method == Method::OPTIONS || method_route(matched.value, method).is_some())
+    }
+
+    /// Tells whether the handlers of a route read the request body themselves, see [`Router::add_streaming`].
+    pub fn streams_body(&self, method: &Method, path: &str) -> bool {
+        let route = self.routes.find(path)
+            .and_then(|matched| method_route(matched.value, method));
+        matches!(route, Some(Route::Local(LocalRoute { streaming: true, .. })))
+    }
+
+    /// Lists the methods a path can be requested with, for the Allow header of 405 responses,
+    /// or an empty list if no route matches the path.
+    pub fn allowed_methods(&self, path: &str) -> Vec<Method> {
+        let Some(matched) = self.routes.find(path) else {
+            return Vec::new();
+        };
+
+        let mut methods: Vec<Method> = matched.value.keys().cloned().collect();
+        if matched.value.contains_key(&Method::GET) && !matched.value.contains_key(&Method::HEAD) {
+            methods.push(Method::HEAD);
+        }
+        if !matched.value.contains_key(&Method::OPTIONS) {
+            methods.push(Method::OPTIONS);
+        }
+        methods.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+        methods
+    }
+
+    /// Tells how a request is served, or None if it should be answered with 404,
+    /// or with 405 when the path is registered under other methods: those are never forwarded to the passthrough group.
+    pub fn route_mode(&self, method: &Method, path: &str) -> Option<RouteMode> {
+        let matched = self.routes.find(path);
+        let route = matched.as_ref()
+            .and_then(|matched| method_route(matched.value, method));
+
+        match route {
+            Some(Route::Local(_)) => Some(RouteMode::Local),
+            Some(Route::Upstream { group, encrypted }) => Some(RouteMode::Upstream {
+                group: group.clone(),
+                encrypted: *encrypted,
+            }),
+            None if *method == Method::OPTIONS && matched.is_some() => Some(RouteMode::Local),
+            None if matched.is_some() => None,
+            None => self.passthrough.as_ref().map(|group| RouteMode::Upstream {
+                group: group.clone(),
+                encrypted: self.passthrough_encrypted,
+            }),
         }
     }
 
+    /// Adds middleware wrapping every request handled locally, outside of the middleware of the routes.
+    pub fn wrap(&mut self, middleware: Box<[Arc<dyn Middleware>]>) {
+        self.middleware.extend(middleware);
+    }
+
+    pub async fn call_handler(&self, ctx: &mut dyn ContextTrait) -> Response {
+        let dispatch = Dispatch { routes: &self.routes };
+        Next::new(&self.middleware, &dispatch).run(ctx).await
+    }
+
+    /// Starts a group of routes sharing a path prefix and middleware, see [`RouteGroup`].
+    pub fn group(&mut self, prefix: &str) -> RouteGroup<'_> {
+        RouteGroup::new(self, prefix)
+    }
+
     fn get_base_path(&self, path: &str) -> String {
         path.split('?').next().unwrap_or(path).to_string()
     }
 
-    pub fn post(&mut self, path: String, handlers: Box<[HandleMessage<T>]>) {
-        self.posts.insert(self.get_base_path(&path), handlers);
+    /// Registers handlers for `method` on a route pattern, any method being accepted,
+    /// e.g. `Method::from_bytes(b"PURGE")`. GET routes also answer HEAD requests unless a HEAD route is registered.
+    /// Patterns are made of `/`-separated segments, where `{name}` captures a single segment
+    /// and a trailing `{*name}` captures the rest of the path, e.g. `/users/{name}/images/{img}`.
+    /// Captured values are available to the handlers through `ContextTrait::param`.
+    pub fn add(&mut self, method: Method, path: String, handlers: Box<[Arc<dyn Handler>]>) {
+        self.add_wrapped(method, path, Box::new([]), handlers, false);
     }
 
-    pub fn get(&mut self, path: String, handlers: Box<[HandleMessage<T>]>) {
-        self.gets.insert(self.get_base_path(&path), handlers);
+    /// Registers handlers for `method` on a route pattern like [`Router::add`], except that the request body
+    /// is not buffered: the handlers read it with `ContextTrait::read_body_chunk`, e.g. to store a large upload.
+    /// `ContextTrait::get_request_body` stays empty, and `WGPMessageHandler::ntor_decrypt` decrypts the body
+    /// as it is read rather than up front.
+    pub fn add_streaming(&mut self, method: Method, path: String, handlers: Box<[Arc<dyn Handler>]>) {
+        self.add_wrapped(method, path, Box::new([]), handlers, true);
     }
 
-    pub fn put(&mut self, path: String, handlers: Box<[HandleMessage<T>]>) {
-        self.puts.insert(self.get_base_path(&path), handlers);
+    /// Registers handlers for `method` on a route pattern like [`Router::add`] or [`Router::add_streaming`],
+    /// wrapped by `middleware`.
+    pub fn add_wrapped(&mut self, method: Method, path: String, middleware: Box<[Arc<dyn Middleware>]>, handlers: Box<[Arc<dyn Handler>]>, streaming: bool) {
+        let base_path = self.get_base_path(&path);
+        self.routes.entry(&base_path).insert(method, Route::Local(LocalRoute { middleware, handlers, streaming }));
     }
 
-    pub fn delete(&mut self, path: String, handlers: Box<[HandleMessage<T>]>) {
-        self.deletes.insert(self.get_base_path(&path), handlers);
+    /// Registers a route pattern whose `method` requests are forwarded to a peer of the upstream `group`.
+    /// When `encrypted` is set, the proxy decrypts the request body and encrypts the response body
+    /// with the client's nTor session, so the upstream itself does not need to know about nTor.
+    pub fn upstream(&mut self, method: Method, path: String, group: String, encrypted: bool) {
+        let base_path = self.get_base_path(&path);
+        self.routes.entry(&base_path).insert(method, Route::Upstream { group, encrypted });
+    }
+
+    pub fn post(&mut self, path: String, handlers: Box<[Arc<dyn Handler>]>) {
+        self.add(Method::POST, path, handlers);
     }
-}
 
+    pub fn get(&mut self, path: String, handlers: Box<[Arc<dyn Handler>]>) {
+        self.add(Method::GET, path, handlers);
+    }
+
+    pub fn put(&mut self, path: String, handlers: Box<[Arc<dyn Handler>]>) {
+        self.add(Method::PUT, path, handlers);
+    }
+
+    pub fn delete(&mut self, path: String, handlers: Box<[Arc<dyn Handler>]>) {
+        self.add(Method::DELETE, path, handlers);
+    }
 
+    pub fn patch(&mut self, path: String, handlers: Box<[Arc<dyn Handler>]>) {
+        self.add(Method::PATCH, path, handlers);
+    }
+
+    pub fn head(&mut self, path: String, handlers: Box<[Arc<dyn Handler>]>) {
+        self.add(Method::HEAD, path, handlers);
+    }
+}
@@ -0,0 +1,129 @@
+use serde::de::DeserializeOwned;
+
+/// Query holds the percent-decoded parameters of a query string in their original order,
+/// a key being repeatable, e.g. `?tag=poetry&tag=prose&author=Maya%20Angelou`.
+#[derive(Debug, Clone, Default)]
+pub struct Query {
+    raw: String,
+    pairs: Vec<(String, String)>,
+}
+
+impl Query {
+    /// Parses the query component of a URI, without the leading `?`, ignoring a fragment if one is left.
+    /// Queries are decoded with the `application/x-www-form-urlencoded` rules browsers encode forms with,
+    /// deliberately: `+` stands for a space, so a literal `+` must be sent as `%2B`, and invalid UTF-8
+    /// sequences are replaced. Path segments are strictly percent-decoded instead, see [`decode_segment`].
+    pub fn parse(raw: &str) -> Self {
+        let raw = raw.split('#').next().unwrap_or_default();
+        let pairs = form_urlencoded::parse(raw.as_bytes())
+            .map(|(key, value)| (key.into_owned(), value.into_owned()))
+            .collect();
+
+        Query { raw: raw.to_string(), pairs }
+    }
+
+    /// Returns the first value of `key`.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.pairs.iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Returns every value of `key`, in order.
+    pub fn get_all(&self, key: &str) -> Vec<&str> {
+        self.pairs.iter()
+            .filter(|(k, _)| k == key)
+            .map(|(_, value)| value.as_str())
+            .collect()
+    }
+
+    pub fn pairs(&self) -> &[(String, String)] {
+        &self.pairs
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pairs.is_empty()
+    }
+
+    /// Deserializes the query into `T`, repeated keys filling `Vec` fields and missing keys `Option` fields, e.g.
+    /// ```ignore
+    /// #[derive(Deserialize)]
+    /// struct PoemFilter { author: Option<String>, tag: Vec<String>, limit: Option<u32> }
+    /// ```
+    pub fn deserialize<T: DeserializeOwned>(&self) -> Result<T, String> {
+        serde_html_form::from_str(&self.raw).map_err(|err| err.to_string())
+    }
+}
+
+/// Decodes a percent-encoded path segment, such as a path parameter. `+` is kept as it is.
+pub fn decode_segment(segment: &str) -> String {
+    percent_encoding::percent_decode_str(segment).decode_utf8_lossy().into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+    use pingora::http::Method;
+    use super::*;
+    use crate::router::types::{ContextTrait, TestContext};
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    struct PoemFilter {
+        author: Option<String>,
+        #[serde(default)]
+        tag: Vec<String>,
+        limit: Option<u32>,
+    }
+
+    #[test]
+    fn keeps_repeated_keys_in_order() {
+        let query = Query::parse("tag=poetry&author=Frost&tag=prose&tag=");
+
+        assert_eq!(query.get("tag"), Some("poetry"));
+        assert_eq!(query.get_all("tag"), vec!["poetry", "prose", ""]);
+        assert_eq!(query.get_all("missing"), Vec::<&str>::new());
+        assert_eq!(query.pairs().len(), 4);
+    }
+
+    #[test]
+    fn percent_decodes_keys_and_values_with_form_semantics() {
+        let query = Query::parse("author=Maya%20Angelou&q=a+b%2Bc&caf%C3%A9=%E2%98%95&bad=%FF");
+
+        assert_eq!(query.get("author"), Some("Maya Angelou"));
+        // `+` is a space in forms, a literal plus is encoded
+        assert_eq!(query.get("q"), Some("a b+c"));
+        assert_eq!(query.get("café"), Some("☕"));
+        assert_eq!(query.get("bad"), Some("\u{FFFD}"));
+        // path segments are not forms
+        assert_eq!(decode_segment("a+b%2Bc%20d"), "a+b+c d");
+    }
+
+    #[test]
+    fn ignores_fragments() {
+        let query = Query::parse("tag=poetry#tag=prose");
+        assert_eq!(query.get_all("tag"), vec!["poetry"]);
+
+        let ctx = TestContext::new(Method::GET, "/poems?tag=poetry#section");
+        assert_eq!(ctx.query().get_all("tag"), vec!["poetry"]);
+        assert_eq!(ctx.path(), "/poems");
+    }
+
+    #[test]
+    fn query_as_deserializes_repeated_and_missing_keys() {
+        let ctx = TestContext::new(Method::GET, "/poems?tag=poetry&limit=5&tag=prose&author=Robert%20Frost");
+        let ctx: &dyn ContextTrait = &ctx;
+        assert_eq!(ctx.query_as::<PoemFilter>(), Ok(PoemFilter {
+            author: Some("Robert Frost".to_string()),
+            tag: vec!["poetry".to_string(), "prose".to_string()],
+            limit: Some(5),
+        }));
+
+        let ctx = TestContext::new(Method::GET, "/poems");
+        let ctx: &dyn ContextTrait = &ctx;
+        assert_eq!(ctx.query_as::<PoemFilter>(), Ok(PoemFilter { author: None, tag: vec![], limit: None }));
+
+        let ctx = TestContext::new(Method::GET, "/poems?limit=many");
+        let ctx: &dyn ContextTrait = &ctx;
+        assert!(ctx.query_as::<PoemFilter>().is_err());
+    }
+}
@@ -0,0 +1,211 @@
+use std::collections::HashMap;
+
+/// A segment of a route pattern such as `/users/{name}/images/{*rest}`.
+enum Segment<'a> {
+    /// literal segment, e.g. `users`
+    Static(&'a str),
+    /// named parameter matching exactly one segment, e.g. `{name}`
+    Param(&'a str),
+    /// named catch-all matching the rest of the path, e.g. `{*rest}`
+    CatchAll(&'a str),
+}
+
+impl<'a> Segment<'a> {
+    fn parse(segment: &'a str) -> Self {
+        match segment.strip_prefix('{').and_then(|s| s.strip_suffix('}')) {
+            Some(name) => match name.strip_prefix('*') {
+                Some(name) => Segment::CatchAll(name),
+                None => Segment::Param(name),
+            },
+            None => Segment::Static(segment),
+        }
+    }
+}
+
+/// Splits a path into its non-empty segments, so `/poems/` and `/poems` are the same route.
+fn split_path(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|segment| !segment.is_empty())
+}
+
+/// Result of a successful lookup in the [`Tree`].
+pub struct Match<'a, V> {
+    pub value: &'a V,
+    /// captured path parameters in the order they appear in the pattern
+    pub params: Vec<(String, String)>,
+}
+
+/// Segment-based matching trie used by the router.
+/// Lookups walk the tree one segment at a time, preferring static children over parameters,
+/// and parameters over catch-alls, backtracking when a more specific branch does not match.
+pub struct Tree<V> {
+    value: Option<V>,
+    statics: HashMap<String, Tree<V>>,
+    param: Option<(String, Box<Tree<V>>)>,
+    catch_all: Option<(String, V)>,
+}
+
+impl<V> Default for Tree<V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<V> Tree<V> {
+    pub fn new() -> Self {
+        Tree {
+            value: None,
+            statics: HashMap::new(),
+            param: None,
+            catch_all: None,
+        }
+    }
+
+    /// Returns the value stored for `pattern`, inserting a default one if the route is new.
+    /// # Panics
+    /// * If a parameter name conflicts with an already registered one at the same position,
+    ///   or if a catch-all is not the last segment of the pattern.
+    pub fn entry(&mut self, pattern: &str) -> &mut V
+    where
+        V: Default,
+    {
+        let mut node = self;
+        let mut segments = split_path(pattern).peekable();
+
+        while let Some(segment) = segments.next() {
+            match Segment::parse(segment) {
+                Segment::Static(name) => {
+                    node = node.statics.entry(name.to_string()).or_insert_with(Tree::new);
+                }
+                Segment::Param(name) => {
+                    let (existing, child) = node.param
+                        .get_or_insert_with(|| (name.to_string(), Box::new(Tree::new())));
+                    if existing.as_str() != name {
+                        panic!("route {pattern}: parameter {{{name}}} conflicts with {{{existing}}}");
+                    }
+                    node = child.as_mut();
+                }
+                Segment::CatchAll(name) => {
+                    if segments.peek().is_some() {
+                        panic!("route {pattern}: catch-all {{*{name}}} must be the last segment");
+                    }
+                    let (existing, value) = node.catch_all
+                        .get_or_insert_with(|| (name.to_string(), V::default()));
+                    if existing.as_str() != name {
+                        panic!("route {pattern}: catch-all {{*{name}}} conflicts with {{*{existing}}}");
+                    }
+                    return value;
+                }
+            }
+        }
+
+        node.value.get_or_insert_with(V::default)
+    }
+
+    /// Finds the value registered for a concrete request path, capturing its parameters.
+    pub fn find(&self, path: &str) -> Option<Match<'_, V>> {
+        let segments: Vec<&str> = split_path(path).collect();
+        let mut params = Vec::new();
+        self.find_segments(&segments, &mut params)
+            .map(|value| Match { value, params })
+    }
+
+    fn find_segments<'a>(&'a self, segments: &[&str], params: &mut Vec<(String, String)>) -> Option<&'a V> {
+        let Some((first, rest)) = segments.split_first() else {
+            return self.value.as_ref()
+                .or_else(|| self.catch_all.as_ref().map(|(name, value)| {
+                    params.push((name.clone(), String::new()));
+                    value
+                }));
+        };
+
+        if let Some(child) = self.statics.get(*first) {
+            if let Some(value) = child.find_segments(rest, params) {
+                return Some(value);
+            }
+        }
+
+        if let Some((name, child)) = &self.param {
+            params.push((name.clone(), first.to_string()));
+            if let Some(value) = child.find_segments(rest, params) {
+                return Some(value);
+            }
+            params.pop();
+        }
+
+        self.catch_all.as_ref().map(|(name, value)| {
+            params.push((name.clone(), segments.join("/")));
+            value
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tree(patterns: &[&'static str]) -> Tree<&'static str> {
+        let mut tree = Tree::new();
+        for pattern in patterns {
+            *tree.entry(pattern) = *pattern;
+        }
+        tree
+    }
+
+    fn find(tree: &Tree<&'static str>, path: &str) -> Option<(&'static str, Vec<(String, String)>)> {
+        tree.find(path).map(|matched| (*matched.value, matched.params))
+    }
+
+    fn params(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn prefers_static_over_param_over_catch_all() {
+        let tree = tree(&["/poems/latest", "/poems/{id}", "/poems/{*rest}"]);
+
+        assert_eq!(find(&tree, "/poems/latest"), Some(("/poems/latest", vec![])));
+        assert_eq!(find(&tree, "/poems/7"), Some(("/poems/{id}", params(&[("id", "7")]))));
+        assert_eq!(find(&tree, "/poems/7/lines"), Some(("/poems/{*rest}", params(&[("rest", "7/lines")]))));
+    }
+
+    #[test]
+    fn backtracks_when_a_more_specific_branch_does_not_match() {
+        let tree = tree(&["/users/admin/settings", "/users/{name}/images"]);
+
+        // `admin` matches the static child first, which has no `images` below it
+        assert_eq!(find(&tree, "/users/admin/images"), Some(("/users/{name}/images", params(&[("name", "admin")]))));
+        assert_eq!(find(&tree, "/users/admin/settings"), Some(("/users/admin/settings", vec![])));
+    }
+
+    #[test]
+    fn drops_the_params_of_a_failed_branch() {
+        let tree = tree(&["/files/{id}/raw", "/files/{*rest}"]);
+
+        assert_eq!(find(&tree, "/files/7/raw"), Some(("/files/{id}/raw", params(&[("id", "7")]))));
+        assert_eq!(find(&tree, "/files/7/meta"), Some(("/files/{*rest}", params(&[("rest", "7/meta")]))));
+    }
+
+    #[test]
+    fn trailing_catch_all_matches_the_rest_of_the_path() {
+        let tree = tree(&["/static/{*rest}"]);
+
+        assert_eq!(find(&tree, "/static/css/main.css"), Some(("/static/{*rest}", params(&[("rest", "css/main.css")]))));
+        assert_eq!(find(&tree, "/static/"), Some(("/static/{*rest}", params(&[("rest", "")]))));
+        assert_eq!(find(&tree, "/static"), Some(("/static/{*rest}", params(&[("rest", "")]))));
+        assert_eq!(find(&tree, "/other"), None);
+    }
+
+    #[test]
+    fn ignores_empty_segments() {
+        let tree = tree(&["/poems"]);
+
+        assert_eq!(find(&tree, "/poems/"), Some(("/poems", vec![])));
+        assert_eq!(find(&tree, "//poems"), Some(("/poems", vec![])));
+    }
+
+    #[test]
+    #[should_panic(expected = "must be the last segment")]
+    fn rejects_catch_all_before_the_last_segment() {
+        tree(&["/files/{*rest}/raw"]);
+    }
+}
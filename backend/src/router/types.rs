@@ -1,22 +1,68 @@
 use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Arc;
+use http::header::VARY;
+use http::{HeaderMap, HeaderName, HeaderValue};
 use pingora::http::{Method, RequestHeader, StatusCode};
 use pingora::proxy::Session;
+use serde::de::DeserializeOwned;
+use crate::error::WgpError;
+use crate::format::Format;
+use crate::router::body::{BodyDecoder, BodyStream};
+use crate::router::query::Query;
 
 #[derive(Debug, Clone)]
 pub struct RequestSummary {
     pub method: Method,
+    /// the path of the URI, still percent-encoded so that an encoded `/` does not split a segment
     pub path: String,
+    /// the first value of each query parameter
     pub params: HashMap<String, String>,
+    /// path parameters such as `{id}`, set by the router once the route is matched
+    pub path_params: HashMap<String, String>,
+    pub query: Query,
 }
 
-pub trait ContextTrait {
+impl RequestSummary {
+    /// Returns a path or query parameter. Path parameters take precedence over query parameters
+    /// of the same name, so that `/poems/1?id=2` is poem 1 whatever the query says.
+    pub fn param(&self, key: &str) -> Option<&String> {
+        self.path_params.get(key).or_else(|| self.params.get(key))
+    }
+
+    /// Returns the path parameters and the first value of each query parameter, see [`RequestSummary::param`].
+    pub fn params(&self) -> HashMap<String, String> {
+        self.params.keys()
+            .chain(self.path_params.keys())
+            .filter_map(|key| self.param(key).map(|value| (key.clone(), value.clone())))
+            .collect()
+    }
+}
+
+/// ContextTrait is Send so that handlers can hold it across await points.
+pub trait ContextTrait: Send {
     fn method(&self) -> Method;
     fn path(&self) -> &str;
     fn param(&self, key: &str) -> Option<&String>;
-    fn params(&self) -> &HashMap<String, String>;
+    fn params(&self) -> HashMap<String, String>;
+    /// the query parameters, including the repeated ones
+    fn query(&self) -> &Query;
+    /// set a path parameter, used by the router to expose path parameters such as `{id}`
+    fn set_param(&mut self, key: String, value: String);
     fn request_header(&self) -> &RequestHeader;
     fn set_request_body(&mut self, body: Vec<u8>);
     fn get_request_body(&self) -> &Vec<u8>;
+    /// Tells whether the request body is left for the handlers to read chunk by chunk, see `Router::add_streaming`,
+    /// in which case `get_request_body` stays empty.
+    fn streams_body(&self) -> bool;
+    /// Reads the next chunk of a request body left unbuffered by the router, through its decoder if it has one.
+    /// Returns None once the body is complete, and WgpError::PayloadTooLarge as soon as it exceeds the maximum body size.
+    fn read_body_chunk(&mut self) -> BodyChunkFuture<'_>;
+    /// Decodes the rest of a streamed request body with `decoder` before the handlers read it,
+    /// e.g. decrypts it with `WGPMessageHandler::ntor_decrypt`. Buffered bodies are decoded as a whole instead.
+    fn set_body_decoder(&mut self, decoder: Box<dyn BodyDecoder>);
     fn set_response_body(&mut self, body: Vec<u8>);
     fn get_response_body(&self) -> &Vec<u8>;
     fn session(&self) -> &Session;
@@ -26,43 +72,241 @@ pub trait ContextTrait {
     fn set(&mut self, key: String, value: String);
 }
 
+/// key of the context memory overriding the format of the request body
+const REQUEST_FORMAT: &str = "request_format";
+
+impl dyn ContextTrait + '_ {
+    /// Parses a path or query parameter into `V`.
+    /// Returns None if the parameter is missing or cannot be parsed.
+    pub fn param_as<V: FromStr>(&self, key: &str) -> Option<V> {
+        self.param(key).and_then(|value| value.parse().ok())
+    }
+
+    /// Deserializes the query parameters into `T`, see [`Query::deserialize`].
+    pub fn query_as<T: DeserializeOwned>(&self) -> Result<T, String> {
+        self.query().deserialize()
+    }
+
+    /// Returns the format of the request body: the format of its `Content-Type`, unless a handler
+    /// decoding the body overrode it, see [`set_request_format`](Self::set_request_format).
+    pub fn request_format(&self) -> Result<Format, WgpError> {
+        match self.get(REQUEST_FORMAT).and_then(|media_type| Format::from_media_type(media_type)) {
+            Some(format) => Ok(format),
+            None => Format::of_request(self.request_header()),
+        }
+    }
+
+    /// Overrides the format of the request body for the rest of the route,
+    /// e.g. once `WGPMessageHandler::ntor_decrypt` opened a binary envelope telling the format of its content.
+    pub fn set_request_format(&mut self, format: Format) {
+        self.set(REQUEST_FORMAT.to_string(), format.media_type().to_string());
+    }
+
+    /// Chooses the format of the response, wildcards selecting the format of the request body, see [`Format::negotiate_or`].
+    pub fn negotiate_format(&self) -> Result<Format, WgpError> {
+        Format::negotiate_or(self.request_header(), self.request_format().unwrap_or_default())
+    }
+
+    /// Returns the format the client asked responses in, JSON if it accepts none of the supported formats.
+    pub fn response_format(&self) -> Format {
+        self.negotiate_format().unwrap_or_default()
+    }
+}
+
 pub struct Response {
     pub status: StatusCode,
     pub body: Option<Vec<u8>>,
+    /// a body sent chunk by chunk instead of `body`, e.g. the content of a file
+    pub stream: Option<Box<dyn BodyStream>>,
+    /// headers merged into the response header by the proxy, e.g. `Cache-Control`, `Set-Cookie` or `Location`.
+    /// Content-Type is set by the serialized bodies, see [`IntoResponse`], and defaults to binary otherwise,
+    /// Content-Length and Transfer-Encoding are always set by the proxy.
+    pub headers: HeaderMap,
 }
 
 impl Response {
     pub fn new(status: StatusCode, body: Option<Vec<u8>>) -> Self {
-        Response { status, body }
+        Response { status, body, stream: None, headers: HeaderMap::new() }
+    }
+
+    /// Builds a response whose body is streamed, see [`BodyStream`].
+    pub fn streamed(status: StatusCode, stream: Box<dyn BodyStream>) -> Self {
+        Response { status, body: None, stream: Some(stream), headers: HeaderMap::new() }
+    }
+
+    /// Sets a header, replacing the values it had, e.g.
+    /// `Response::new(StatusCode::OK, body).with_header(header::CACHE_CONTROL, HeaderValue::from_static("no-store"))`.
+    pub fn with_header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        self.headers.insert(name, value);
+        self
+    }
+
+    /// Lists `Accept` in the Vary header unless it already is, for bodies serialized in the format
+    /// negotiated with the client, see [`Format::negotiate`].
+    pub fn vary_on_accept(mut self) -> Self {
+        let listed = self.headers.get_all(VARY).iter()
+            .filter_map(|vary| vary.to_str().ok())
+            .flat_map(|vary| vary.split(','))
+            .any(|name| name.trim().eq_ignore_ascii_case("accept") || name.trim() == "*");
+        if !listed {
+            self.headers.append(VARY, HeaderValue::from_static("Accept"));
+        }
+        self
+    }
+}
+
+/// IntoResponse converts the outcome of a handler into the response sent for it,
+/// e.g. a [`WgpError`] into its status and JSON error body.
+pub trait IntoResponse {
+    fn into_response(self) -> Response;
+
+    /// Converts into a response whose body is serialized in `format`, as negotiated with the client.
+    /// Defaults to [`IntoResponse::into_response`] for responses whose body is already serialized.
+    fn into_response_as(self, _format: Format) -> Response
+    where
+        Self: Sized,
+    {
+        self.into_response()
+    }
+}
+
+impl IntoResponse for Response {
+    fn into_response(self) -> Response {
+        self
+    }
+}
+
+/// Overrides the status of a response, e.g. `(StatusCode::CREATED, body)`.
+impl<T: IntoResponse> IntoResponse for (StatusCode, T) {
+    fn into_response(self) -> Response {
+        self.into_response_as(Format::Json)
+    }
+
+    fn into_response_as(self, format: Format) -> Response {
+        let (status, response) = self;
+        let mut response = response.into_response_as(format);
+        response.status = status;
+        response
     }
 }
 
+impl<T: IntoResponse, E: IntoResponse> IntoResponse for Result<T, E> {
+    fn into_response(self) -> Response {
+        self.into_response_as(Format::Json)
+    }
+
+    fn into_response_as(self, format: Format) -> Response {
+        match self {
+            Ok(ok) => ok.into_response_as(format),
+            Err(err) => err.into_response_as(format),
+        }
+    }
+}
+
+/// BodyChunkFuture is the future returned by `ContextTrait::read_body_chunk`.
+pub type BodyChunkFuture<'a> = Pin<Box<dyn Future<Output = Result<Option<Vec<u8>>, WgpError>> + Send + 'a>>;
+
+/// RouteMode tells the proxy how a request is served.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RouteMode {
+    /// handled locally by the router's handlers
+    Local,
+    /// forwarded to a peer of the named upstream group, decrypting the request and encrypting the response
+    /// with the client's nTor session when `encrypted` is set
+    Upstream { group: String, encrypted: bool },
+}
+
 // Box<dyn std::error::Error + Send + Sync> is used to represent any error type that implements the std::error::Error trait and can be sent across thread boundaries.
-pub type HandleMessage<T> = fn(&T, &mut dyn ContextTrait) -> Response;
+pub type HandleMessage<T> = for<'a> fn(&'a T, &'a mut dyn ContextTrait) -> HandlerFuture<'a>;
+
+/// HandlerFuture is the future returned by a handler, borrowing the handler and the context until it completes.
+pub type HandlerFuture<'a> = Pin<Box<dyn Future<Output = Response> + Send + 'a>>;
+
+/// Handler is one step of a route, such as a middleware or the endpoint itself.
+/// Handlers carry their own state, so routes backed by different services can be mounted on the same router.
+/// Closures are handlers too, see [`handler_fn`].
+pub trait Handler: Send + Sync {
+    fn call<'a>(&'a self, ctx: &'a mut dyn ContextTrait) -> HandlerFuture<'a>;
+}
+
+impl<F> Handler for F
+where
+    F: for<'a> Fn(&'a mut dyn ContextTrait) -> HandlerFuture<'a> + Send + Sync,
+{
+    fn call<'a>(&'a self, ctx: &'a mut dyn ContextTrait) -> HandlerFuture<'a> {
+        self(ctx)
+    }
+}
+
+/// ServiceHandler is a handler calling an async method of a shared service, e.g. `WGPMessageHandler::ntor_close`.
+/// Routes are usually built from these with the `handlers!` and `typed_handlers!` macros.
+pub struct ServiceHandler<T> {
+    service: Arc<T>,
+    handle: HandleMessage<T>,
+}
+
+impl<T> ServiceHandler<T> {
+    pub fn new(service: Arc<T>, handle: HandleMessage<T>) -> Self {
+        ServiceHandler { service, handle }
+    }
+}
+
+impl<T: Send + Sync> Handler for ServiceHandler<T> {
+    fn call<'a>(&'a self, ctx: &'a mut dyn ContextTrait) -> HandlerFuture<'a> {
+        (self.handle)(&self.service, ctx)
+    }
+}
+
+/// Turns a closure into a handler, capturing whatever configuration or dependencies the route needs, e.g.
+/// `handler_fn(move |ctx| { let greeting = greeting.clone(); Box::pin(async move { ... }) })`.
+pub fn handler_fn<F>(f: F) -> Arc<dyn Handler>
+where
+    F: for<'a> Fn(&'a mut dyn ContextTrait) -> HandlerFuture<'a> + Send + Sync + 'static,
+{
+    Arc::new(f)
+}
 
 /// Context is a context for handling WGP requests.
 /// It implements the ContextTrait to provide access to request details and session information.
 /// It is expected to simplify or customize the usage of pingora::proxy::Session, particularly for this repository.
 pub struct Context<'a> {
     request_summary: RequestSummary,
-    request_header: &'a RequestHeader,
     request_body: Vec<u8>,
     response_body: Vec<u8>,
-    session: &'a Session,
+    session: &'a mut Session,
+    // set when the handlers read the request body from the session themselves, see Router::add_streaming
+    streamed_body: Option<StreamedBody>,
     memory: HashMap<String, String>, // for storing key-value pairs
 }
 
+/// StreamedBody keeps track of a request body read chunk by chunk from the session.
+struct StreamedBody {
+    // size of the body read so far, and the limit it may not exceed
+    read: usize,
+    max_body_bytes: usize,
+    decoder: Option<Box<dyn BodyDecoder>>,
+    complete: bool,
+}
+
 impl<'a> Context<'a> {
-    pub(crate) fn new(request_summary: RequestSummary, body: Vec<u8>, session: &'a Session) -> Self {
+    /// Creates the context of a request whose body was buffered beforehand.
+    pub(crate) fn new(request_summary: RequestSummary, body: Vec<u8>, session: &'a mut Session) -> Self {
         Context {
             request_summary,
-            request_header: session.req_header(),
             request_body: body,
             response_body: vec![],
             session,
+            streamed_body: None,
             memory: HashMap::new(), // Initialize an empty HashMap for memory
         }
     }
+
+    /// Creates the context of a request whose body is left in the session, for the handlers to read with `read_body_chunk`.
+    pub(crate) fn streaming(request_summary: RequestSummary, session: &'a mut Session, max_body_bytes: usize) -> Self {
+        let mut context = Context::new(request_summary, vec![], session);
+        context.streamed_body = Some(StreamedBody { read: 0, max_body_bytes, decoder: None, complete: false });
+        context
+    }
 }
 
 impl<'a> ContextTrait for Context<'a> {
@@ -75,15 +319,23 @@ impl<'a> ContextTrait for Context<'a> {
     }
 
     fn param(&self, key: &str) -> Option<&String> {
-        self.request_summary.params.get(key)
+        self.request_summary.param(key)
+    }
+
+    fn params(&self) -> HashMap<String, String> {
+        self.request_summary.params()
+    }
+
+    fn query(&self) -> &Query {
+        &self.request_summary.query
     }
 
-    fn params(&self) -> &HashMap<String, String> {
-        &self.request_summary.params
+    fn set_param(&mut self, key: String, value: String) {
+        self.request_summary.path_params.insert(key, value);
     }
 
     fn request_header(&self) -> &RequestHeader {
-        self.request_header
+        self.session.req_header()
     }
 
     fn set_request_body(&mut self, data: Vec<u8>) {
@@ -94,6 +346,48 @@ impl<'a> ContextTrait for Context<'a> {
         &self.request_body
     }
 
+    fn streams_body(&self) -> bool {
+        self.streamed_body.is_some()
+    }
+
+    fn read_body_chunk(&mut self) -> BodyChunkFuture<'_> {
+        Box::pin(async move {
+            let Some(body) = self.streamed_body.as_mut() else {
+                return Ok(None);
+            };
+
+            // a decoder may need more than a chunk to decode anything, e.g. a whole frame
+            while !body.complete {
+                let chunk = match self.session.read_request_body().await {
+                    Ok(chunk) => chunk.map(|chunk| chunk.to_vec()),
+                    Err(err) => return Err(WgpError::InvalidBody(err.to_string())),
+                };
+                body.complete = chunk.is_none();
+                let chunk = chunk.unwrap_or_default();
+
+                body.read += chunk.len();
+                if body.read > body.max_body_bytes {
+                    return Err(WgpError::PayloadTooLarge);
+                }
+
+                let chunk = match body.decoder.as_mut() {
+                    Some(decoder) => decoder.decode(chunk, body.complete).await?,
+                    None => chunk,
+                };
+                if !chunk.is_empty() {
+                    return Ok(Some(chunk));
+                }
+            }
+            Ok(None)
+        })
+    }
+
+    fn set_body_decoder(&mut self, decoder: Box<dyn BodyDecoder>) {
+        if let Some(body) = self.streamed_body.as_mut() {
+            body.decoder = Some(decoder);
+        }
+    }
+
     fn set_response_body(&mut self, body: Vec<u8>) {
         self.response_body = body
     }
@@ -113,4 +407,99 @@ impl<'a> ContextTrait for Context<'a> {
     fn set(&mut self, key: String, value: String) {
         self.memory.insert(key, value);
     }
-}
\ No newline at end of file
+}
+/// TestContext is a context without a client connection for unit tests, enough for the router which never reads the session.
+#[cfg(test)]
+pub(crate) struct TestContext {
+    request_summary: RequestSummary,
+    request_header: RequestHeader,
+    request_body: Vec<u8>,
+    response_body: Vec<u8>,
+    memory: HashMap<String, String>,
+}
+
+#[cfg(test)]
+impl TestContext {
+    /// `uri` is the path and query of the request, e.g. `/poems?tag=prose`.
+    pub(crate) fn new(method: Method, uri: &str) -> Self {
+        let request_header = RequestHeader::build(method.clone(), uri.as_bytes(), None).unwrap();
+        let query = Query::parse(request_header.uri.query().unwrap_or_default());
+        let mut params = HashMap::new();
+        for (key, value) in query.pairs() {
+            params.entry(key.clone()).or_insert_with(|| value.clone());
+        }
+
+        TestContext {
+            request_summary: RequestSummary {
+                method,
+                path: request_header.uri.path().to_string(),
+                params,
+                path_params: HashMap::new(),
+                query,
+            },
+            request_header,
+            request_body: Vec::new(),
+            response_body: Vec::new(),
+            memory: HashMap::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+impl ContextTrait for TestContext {
+    fn method(&self) -> Method { self.request_summary.method.clone() }
+    fn path(&self) -> &str { &self.request_summary.path }
+    fn param(&self, key: &str) -> Option<&String> { self.request_summary.param(key) }
+    fn params(&self) -> HashMap<String, String> { self.request_summary.params() }
+    fn query(&self) -> &Query { &self.request_summary.query }
+    fn set_param(&mut self, key: String, value: String) { self.request_summary.path_params.insert(key, value); }
+    fn request_header(&self) -> &RequestHeader { &self.request_header }
+    fn set_request_body(&mut self, body: Vec<u8>) { self.request_body = body }
+    fn get_request_body(&self) -> &Vec<u8> { &self.request_body }
+    fn streams_body(&self) -> bool { false }
+    fn read_body_chunk(&mut self) -> BodyChunkFuture<'_> { Box::pin(async { Ok(None) }) }
+    fn set_body_decoder(&mut self, _decoder: Box<dyn BodyDecoder>) {}
+    fn set_response_body(&mut self, body: Vec<u8>) { self.response_body = body }
+    fn get_response_body(&self) -> &Vec<u8> { &self.response_body }
+    fn session(&self) -> &Session { unreachable!("the router never reads the session") }
+    fn get(&self, key: &str) -> Option<&String> { self.memory.get(key) }
+    fn set(&mut self, key: String, value: String) { self.memory.insert(key, value); }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn path_params_take_precedence_over_query_params() {
+        let query = Query::parse("id=2&limit=5");
+        let mut summary = RequestSummary {
+            method: Method::GET,
+            path: "/poems/1".to_string(),
+            params: query.pairs().iter().cloned().collect(),
+            path_params: HashMap::new(),
+            query,
+        };
+        summary.path_params.insert("id".to_string(), "1".to_string());
+
+        assert_eq!(summary.param("id").map(String::as_str), Some("1"));
+        assert_eq!(summary.param("limit").map(String::as_str), Some("5"));
+        assert_eq!(summary.params(), HashMap::from([
+            ("id".to_string(), "1".to_string()),
+            ("limit".to_string(), "5".to_string()),
+        ]));
+    }
+
+    #[test]
+    fn accept_is_listed_in_vary_once() {
+        let vary = |response: Response| response.headers.get_all(VARY).iter().map(|vary| vary.to_str().unwrap().to_string()).collect::<Vec<_>>();
+
+        assert_eq!(vary(Response::new(StatusCode::OK, None).vary_on_accept().vary_on_accept()), ["Accept"]);
+        let response = Response::new(StatusCode::OK, None).with_header(VARY, HeaderValue::from_static("Origin"));
+        assert_eq!(vary(response.vary_on_accept()), ["Origin", "Accept"]);
+        let response = Response::new(StatusCode::OK, None).with_header(VARY, HeaderValue::from_static("Origin, accept"));
+        assert_eq!(vary(response.vary_on_accept()), ["Origin, accept"]);
+        let response = Response::new(StatusCode::OK, None).with_header(VARY, HeaderValue::from_static("*"));
+        assert_eq!(vary(response.vary_on_accept()), ["*"]);
+    }
+}
@@ -0,0 +1,216 @@
+use serde::{Deserialize, Serialize};
+use crate::error::WgpError;
+
+/// FieldError tells why a field of a request failed validation, e.g.
+/// `{"field":"username","code":"too_short","message":"must be at least 3 characters long"}`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FieldError {
+    pub field: String,
+    pub code: String,
+    pub message: String,
+}
+
+/// Validator collects the errors of the validation rules declared on a request, at most one per field, e.g.
+/// ```ignore
+/// validator.field("username", &self.username).required().length(3, 32).charset(is_username_char, "letters, digits, '.', '-' and '_'");
+/// validator.field("email", &self.email).email();
+/// ```
+#[derive(Debug, Default)]
+pub struct Validator {
+    errors: Vec<FieldError>,
+}
+
+impl Validator {
+    pub fn new() -> Self {
+        Validator::default()
+    }
+
+    /// Starts the rules of a field, which are checked in order until one of them fails.
+    pub fn field<'v>(&'v mut self, name: &str, value: &'v str) -> FieldRules<'v> {
+        FieldRules { validator: self, name: name.to_string(), value, failed: false }
+    }
+
+    /// Returns WgpError::Validation with every field error, if any rule failed.
+    pub fn finish(self) -> Result<(), WgpError> {
+        if self.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(WgpError::Validation(self.errors))
+        }
+    }
+}
+
+/// FieldRules are the validation rules of one field, see [`Validator::field`].
+/// Fields that are not required are only checked when they are not empty.
+pub struct FieldRules<'v> {
+    validator: &'v mut Validator,
+    name: String,
+    value: &'v str,
+    failed: bool,
+}
+
+impl FieldRules<'_> {
+    /// The field must not be empty or blank.
+    pub fn required(self) -> Self {
+        let valid = !self.value.trim().is_empty();
+        self.check(valid, "required", || "is required".to_string())
+    }
+
+    /// The field must be between `min` and `max` characters long.
+    pub fn length(self, min: usize, max: usize) -> Self {
+        let length = self.value.chars().count();
+        if self.value.is_empty() {
+            return self;
+        }
+
+        let this = self.check(length >= min, "too_short", || format!("must be at least {} characters long", min));
+        this.check(length <= max, "too_long", || format!("must be at most {} characters long", max))
+    }
+
+    /// Every character of the field must be `allowed`, `description` naming them in the error message.
+    pub fn charset(self, allowed: impl Fn(char) -> bool, description: &str) -> Self {
+        let valid = self.value.chars().all(allowed);
+        self.check(valid, "invalid_charset", || format!("must only contain {}", description))
+    }
+
+    /// The field must look like an email address: `local@domain.tld`, without whitespace.
+    // no request body has an email field yet
+    #[allow(dead_code)]
+    pub fn email(self) -> Self {
+        let valid = self.value.is_empty() || is_email(self.value);
+        self.check(valid, "invalid_email", || "must be a valid email address".to_string())
+    }
+
+    fn check(mut self, valid: bool, code: &str, message: impl FnOnce() -> String) -> Self {
+        if self.failed || valid {
+            return self;
+        }
+
+        self.failed = true;
+        self.validator.errors.push(FieldError {
+            field: self.name.clone(),
+            code: code.to_string(),
+            message: message(),
+        });
+        self
+    }
+}
+
+fn is_email(value: &str) -> bool {
+    if value.chars().any(char::is_whitespace) {
+        return false;
+    }
+
+    let Some((local, domain)) = value.split_once('@') else {
+        return false;
+    };
+
+    !local.is_empty()
+        && !domain.contains('@')
+        && domain.split('.').count() >= 2
+        && domain.split('.').all(|label| !label.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use http::header::CONTENT_TYPE;
+    use pingora::http::StatusCode;
+    use serde_json::{json, Value};
+    use super::*;
+    use crate::router::types::IntoResponse;
+
+    fn errors(validate: impl FnOnce(&mut Validator)) -> Vec<(String, String)> {
+        let mut validator = Validator::new();
+        validate(&mut validator);
+        validator.errors.into_iter().map(|error| (error.field, error.code)).collect()
+    }
+
+    fn error(field: &str, code: &str) -> (String, String) {
+        (field.to_string(), code.to_string())
+    }
+
+    #[test]
+    fn length_bounds_are_inclusive_and_count_characters() {
+        let length = |value: &str| errors(|validator| { validator.field("name", value).length(3, 5); });
+
+        assert_eq!(length("ab"), vec![error("name", "too_short")]);
+        assert!(length("abc").is_empty());
+        assert!(length("abcde").is_empty());
+        assert_eq!(length("abcdef"), vec![error("name", "too_long")]);
+        // five characters but ten bytes
+        assert!(length("ééééé").is_empty());
+        // empty fields are left to `required`
+        assert!(length("").is_empty());
+    }
+
+    #[test]
+    fn charset_rejects_any_character_outside_it() {
+        let charset = |value: &str| errors(|validator| {
+            validator.field("name", value).charset(|c| c.is_ascii_lowercase(), "lowercase letters");
+        });
+
+        assert!(charset("abc").is_empty());
+        assert!(charset("").is_empty());
+        assert_eq!(charset("abC"), vec![error("name", "invalid_charset")]);
+        assert_eq!(charset("a c"), vec![error("name", "invalid_charset")]);
+    }
+
+    #[test]
+    fn required_rejects_blank_values() {
+        let required = |value: &str| errors(|validator| { validator.field("name", value).required(); });
+
+        assert_eq!(required(""), vec![error("name", "required")]);
+        assert_eq!(required("  "), vec![error("name", "required")]);
+        assert!(required("a").is_empty());
+    }
+
+    #[test]
+    fn email_requires_a_local_part_and_a_dotted_domain() {
+        let email = |value: &str| errors(|validator| { validator.field("email", value).email(); });
+
+        assert!(email("me@example.com").is_empty());
+        assert!(email("first.last+tag@mail.example.org").is_empty());
+        // empty fields are left to `required`
+        assert!(email("").is_empty());
+        for invalid in ["me", "me@example", "@example.com", "me@@example.com", "me@a@example.com", "me @example.com", "me@example..com", "me@.com", "me@example."] {
+            assert_eq!(email(invalid), vec![error("email", "invalid_email")], "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn only_the_first_failing_rule_of_a_field_is_reported() {
+        let found = errors(|validator| {
+            validator.field("name", "A").required().length(3, 5).charset(|c| c.is_ascii_lowercase(), "lowercase letters");
+            validator.field("other", "").required().length(3, 5);
+        });
+
+        assert_eq!(found, vec![error("name", "too_short"), error("other", "required")]);
+    }
+
+    #[test]
+    fn finish_succeeds_without_errors() {
+        let mut validator = Validator::new();
+        validator.field("name", "abc").required().length(3, 5);
+        assert!(validator.finish().is_ok());
+    }
+
+    #[test]
+    fn validation_errors_are_answered_with_their_details() {
+        let mut validator = Validator::new();
+        validator.field("username", "ab").required().length(3, 32);
+        validator.field("password", "").required();
+        let response = validator.finish().unwrap_err().into_response();
+
+        assert_eq!(response.status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(response.headers.get(CONTENT_TYPE).unwrap(), "application/json");
+        let body: Value = serde_json::from_slice(&response.body.unwrap()).unwrap();
+        assert_eq!(body, json!({
+            "error": "invalid fields: username, password",
+            "code": "validation_failed",
+            "details": [
+                {"field": "username", "code": "too_short", "message": "must be at least 3 characters long"},
+                {"field": "password", "code": "required", "message": "is required"},
+            ],
+        }));
+    }
+}
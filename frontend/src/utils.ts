@@ -31,17 +31,26 @@ export const isLoggedIn = computed(() => {
     return getCookie("jwt") !== undefined && getCookie("jwt")?.length > 0; // todo
 })
 
-export function toBlob(filename: string, bytes: Uint8Array | number[] | ArrayBuffer): Blob | null {
-    if (bytes && bytes.length > 0) {
-        if (!(bytes instanceof Uint8Array)) {
-            bytes = new Uint8Array(bytes);
-        }
-        return new File([bytes], filename, {type: "image/jpeg"});
+// byte fields are base64 strings in JSON responses, and arrays of numbers in older ones
+export function toBytes(bytes: string | Uint8Array | number[] | ArrayBuffer): Uint8Array {
+    if (typeof bytes === "string") {
+        return Uint8Array.from(atob(bytes), c => c.charCodeAt(0));
+    }
+    if (bytes instanceof Uint8Array) {
+        return bytes;
+    }
+    return new Uint8Array(bytes);
+}
+
+export function toBlob(filename: string, bytes: string | Uint8Array | number[] | ArrayBuffer): Blob | null {
+    let content = bytes ? toBytes(bytes) : null;
+    if (content && content.length > 0) {
+        return new File([content], filename, {type: "image/jpeg"});
     }
     return null
 }
 
-export function toImageUrl(filename: string, bytes: Uint8Array | number[] | ArrayBuffer): string | null {
+export function toImageUrl(filename: string, bytes: string | Uint8Array | number[] | ArrayBuffer): string | null {
     let blob = toBlob(filename, bytes);
     if (blob == null) {
         return null
@@ -59,9 +68,9 @@ const BackendBaseURL = "http://localhost:6191";
 export const NTorInitApi = `${BackendBaseURL}/ntor_init`;
 export const LoginApi = `${BackendBaseURL}/login`;
 export const RegisterApi = `${BackendBaseURL}/register`;
-export const GetImageApi = `${BackendBaseURL}/images?id=`;
+export const GetImageApi = `${BackendBaseURL}/images/`;
 export const GetImagesApi = `${BackendBaseURL}/images`;
-export const GetPoemApi = `${BackendBaseURL}/poems?id=`;
+export const GetPoemApi = `${BackendBaseURL}/poems/`;
 export const GetPoemsApi = `${BackendBaseURL}/poems`;
 export const GetProfileApi = `${BackendBaseURL}/profile`;
 